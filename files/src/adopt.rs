@@ -0,0 +1,319 @@
+use crate::{
+    args::Adopt, change::Change, diff, file_contents_match, find_sources,
+    is_linked_to, print_result, rebase, resolve_states, template::Templates,
+    walk_source, with_root, Context, FileState, NkProvisionStateResult,
+    NkProvisionStateStatus, State,
+};
+use anyhow::Result;
+use camino::{Utf8Path, Utf8PathBuf};
+use std::{collections::BTreeMap, io::stdin};
+
+/// Copy edits made to destinations back over the nk source files they're
+/// provisioned from
+pub fn adopt(args: Adopt) {
+    let states: Vec<State> = match serde_json::from_reader(stdin()) {
+        Ok(v) => v,
+        Err(e) => {
+            print_failure(&format!("{e}: failed deserializing"));
+            return;
+        }
+    };
+
+    // NOTE: adopting is backed up too, so it can be undone with restore
+    let context = match Context::new(args.dry_run) {
+        Ok(context) => Context {
+            max_diff_lines: args.max_diff_lines,
+            templates: Templates::new(&args.info.vars),
+            root: args.root,
+            nk_sources: args.info.sources,
+            ..context
+        },
+        Err(e) => {
+            print_failure(&format!("{e:#}"));
+            return;
+        }
+    };
+
+    let destination = Utf8PathBuf::from(
+        shellexpand::tilde(args.destination.as_str()).as_ref(),
+    );
+    let destination = match &context.root {
+        Some(root) => with_root(root, &destination),
+        None => destination,
+    };
+
+    // resolve states just as they're provisioned, keeping those which
+    // provision anything at (or beneath) the destination
+    let mut file_states = vec![];
+
+    for (state, resolved) in resolve_states(&context, states, args.info.user) {
+        let State::Files(state) = state else {
+            continue;
+        };
+
+        if let Err(e) = resolved {
+            print_state_failure(&context, &state, &e);
+            continue;
+        }
+
+        if destination.starts_with(&state.destination)
+            || state.destination.starts_with(&destination)
+        {
+            file_states.push(state);
+        }
+    }
+
+    let destination_files = if destination.is_dir() && !destination.is_symlink()
+    {
+        provisioned_beneath(&context, &file_states, &destination)
+    } else {
+        // NOTE: later states replace what earlier ones provisioned
+        file_states
+            .iter()
+            .rev()
+            .find(|state| destination.starts_with(&state.destination))
+            .map(|state| (destination.clone(), state))
+            .into_iter()
+            .collect()
+    };
+
+    if destination_files.is_empty() {
+        print_failure(&format!(
+            "{destination}: isn't provisioned by any of the states"
+        ));
+        return;
+    }
+
+    for (destination_file, state) in destination_files {
+        let mut result = NkProvisionStateResult {
+            status: NkProvisionStateStatus::Success,
+            changed: false,
+            description: format!(
+                "adopt {}",
+                context.display_path(&destination_file)
+            ),
+            output: String::new(),
+        };
+
+        // NOTE: result is exclusively used to make it's implementation
+        // cleaner (so we can exit if any change fails), all success/failure
+        // details are returned through the mutable result
+        let _ = adopt_file(&context, &mut result, state, &destination_file);
+
+        print_result(&result);
+    }
+}
+
+/// Destinations each state provisions from it's sources beneath a directory
+fn provisioned_beneath<'a>(
+    context: &Context,
+    file_states: &'a [FileState],
+    directory: &Utf8Path,
+) -> BTreeMap<Utf8PathBuf, &'a FileState> {
+    let mut destination_files = BTreeMap::new();
+
+    for state in file_states {
+        let provisioned = (|| {
+            let mut provisioned = vec![];
+
+            for (_, source) in find_sources(context, state)? {
+                for entry in walk_source(&source, &state.destination) {
+                    let (source_file, destination_file) = entry?;
+
+                    if !source_file.is_dir()
+                        && destination_file.starts_with(directory)
+                    {
+                        provisioned.push(destination_file);
+                    }
+                }
+            }
+
+            Ok(provisioned)
+        })();
+
+        match provisioned {
+            Ok(provisioned) => {
+                // NOTE: later states replace what earlier ones provisioned
+                for destination_file in provisioned {
+                    destination_files.insert(destination_file, state);
+                }
+            }
+            Err(e) => print_state_failure(context, state, &e),
+        }
+    }
+
+    destination_files
+}
+
+/// Copy a destination back over the nk source file it's provisioned from
+fn adopt_file(
+    context: &Context,
+    result: &mut NkProvisionStateResult,
+    state: &FileState,
+    destination_file: &Utf8Path,
+) -> Result<(), ()> {
+    let source_files = result.append_check(
+        source_files(context, state, destination_file)
+            .map_err(|e| format!("{e:#}")),
+    )?;
+    let source_file = match source_files.as_slice() {
+        [source_file] => source_file.clone(),
+        [] => {
+            return result.append_check(Err(format!(
+                "{}: does not exist in any nk source",
+                state.source
+            )))
+        }
+        _ => {
+            return result.append_check(Err(format!(
+                "{destination_file}: exists in multiple nk sources, can't \
+                 tell which to adopt it into"
+            )))
+        }
+    };
+
+    // rendering can't be undone
+    if state.template {
+        return result.append_check(Err(format!(
+            "{source_file}: is a template, edit it rather than what it renders"
+        )));
+    }
+
+    // links (and hard links) already are the source, edits and all
+    if source_file.exists() {
+        let is_linked_to = result.append_check(
+            is_linked_to(destination_file, &source_file).map_err(|e| {
+                format!("{e}: failed checking link: {destination_file}")
+            }),
+        )?;
+        if is_linked_to {
+            return Ok(());
+        }
+    }
+
+    if destination_file.is_symlink() {
+        let target = destination_file
+            .read_link_utf8()
+            .map_or_else(|_| "?".into(), |target| target.to_string());
+
+        return result.append_check(Err(format!(
+            "{destination_file}: links to {target}, rather than the nk source"
+        )));
+    }
+    if !destination_file.is_file() {
+        return result
+            .append_check(Err(format!("{destination_file}: is not a file")));
+    }
+
+    if source_file.is_file() {
+        let file_matches = result.append_check(
+            file_contents_match(&source_file, destination_file).map_err(|e| {
+                format!("{e}: failed comparing file: {source_file}")
+            }),
+        )?;
+        if file_matches {
+            return Ok(());
+        }
+
+        // show what's changing in the source
+        let diff = result.append_check(
+            diff::unified_diff(
+                &source_file,
+                destination_file,
+                context.max_diff_lines,
+            )
+            .map_err(|e| format!("{e}: failed diffing file: {source_file}")),
+        )?;
+        result.output.push_str(&diff);
+    }
+
+    // NOTE: the source keeps it's own permissions
+    #[cfg(unix)]
+    let mode = {
+        use std::os::unix::prelude::PermissionsExt;
+
+        source_file
+            .metadata()
+            .ok()
+            .map(|metadata| metadata.permissions().mode() & 0o7777)
+    };
+    #[cfg(not(unix))]
+    let mode = None;
+
+    // delete existing first (files are otherwise just replaced)
+    let backup = state.options.backup();
+    if source_file.is_symlink()
+        || source_file.is_dir()
+        || (backup && source_file.exists())
+    {
+        result.remove_existing(context, &source_file, backup)?;
+    }
+
+    // create parent directory (ie. for files new to a provisioned directory)
+    if let Some(source_parent) = source_file.parent() {
+        let create_parent = Change::CreateParent {
+            path: source_parent.into(),
+            mode: None,
+        };
+        if !create_parent.missing_paths().is_empty() {
+            result.apply_change(context, &create_parent)?;
+        }
+    }
+
+    // copy file
+    result.apply_change(
+        context,
+        &Change::Copy {
+            source: destination_file.into(),
+            destination: source_file,
+            mode,
+        },
+    )
+}
+
+/// Source files a destination could be provisioned from (ie. where it's
+/// source exists in more than one nk source), using the same mapping as
+/// provisioning
+fn source_files(
+    context: &Context,
+    state: &FileState,
+    destination_file: &Utf8Path,
+) -> Result<Vec<Utf8PathBuf>> {
+    let source_files = find_sources(context, state)?
+        .into_iter()
+        .map(|(_, source)| {
+            Ok(rebase(destination_file, &state.destination, &source)?)
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    // NOTE: a file new to a provisioned directory is adopted into it, as long
+    // as it's clear which nk source that is
+    let existing = source_files
+        .iter()
+        .filter(|source_file| source_file.is_symlink() || source_file.exists())
+        .cloned()
+        .collect::<Vec<_>>();
+    Ok(if existing.is_empty() {
+        source_files
+    } else {
+        existing
+    })
+}
+
+/// Report a state which can't be adopted into
+fn print_state_failure(
+    context: &Context,
+    state: &FileState,
+    error: &anyhow::Error,
+) {
+    print_result(&NkProvisionStateResult {
+        status: NkProvisionStateStatus::Failed,
+        changed: false,
+        description: context.display_path(&state.destination),
+        output: format!("{error:#}"),
+    });
+}
+
+fn print_failure(output: &str) {
+    crate::print_failure("files adopt", output);
+}
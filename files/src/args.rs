@@ -1,6 +1,7 @@
+use crate::options::Owner;
 use anyhow::Error;
 use camino::Utf8PathBuf;
-use clap::{arg, Args, Parser, Subcommand};
+use clap::{Args, Parser, Subcommand};
 use serde::Deserialize;
 
 #[derive(Debug, Parser)]
@@ -13,6 +14,11 @@ pub struct Arguments {
 #[derive(Debug, Subcommand)]
 pub enum Commands {
     Provision(Provision),
+    Verify(Verify),
+    Restore(Restore),
+    Adopt(Adopt),
+    Plan(Plan),
+    Apply(Apply),
 }
 
 #[derive(Debug, Args)]
@@ -20,12 +26,132 @@ pub struct Provision {
     /// Provision info as json
     #[arg(value_name = "info", value_parser = ProvisionInfo::value_parser)]
     pub info: ProvisionInfo,
+
+    /// Report the changes that would be made without making them
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// Maximum number of lines shown when diffing changed files
+    #[arg(long, value_name = "lines", default_value_t = 200)]
+    pub max_diff_lines: usize,
+
+    /// Delete previously provisioned files which are no longer declared
+    #[arg(long)]
+    pub prune: bool,
+
+    /// Delete backups from runs older than this many days
+    #[arg(long, value_name = "days", default_value_t = 30)]
+    pub backup_retention_days: u64,
+
+    /// Provision under this directory as if it were `/` (ie. into an image)
+    #[arg(long, value_name = "path")]
+    pub root: Option<Utf8PathBuf>,
+}
+
+/// Check destinations still match their sources, without changing anything
+#[derive(Debug, Args)]
+pub struct Verify {
+    /// Provision info as json
+    #[arg(value_name = "info", value_parser = ProvisionInfo::value_parser)]
+    pub info: ProvisionInfo,
+
+    /// Maximum number of lines shown when diffing drifted files
+    #[arg(long, value_name = "lines", default_value_t = 200)]
+    pub max_diff_lines: usize,
+
+    /// Verify under this directory as if it were `/` (ie. an image)
+    #[arg(long, value_name = "path")]
+    pub root: Option<Utf8PathBuf>,
+}
+
+/// Undo a provisioning run using its backups
+#[derive(Debug, Args)]
+pub struct Restore {
+    /// Backup run to restore (defaults to the most recent run)
+    #[arg(long, value_name = "run")]
+    pub run: Option<String>,
+
+    /// Only restore this destination (and anything beneath it)
+    #[arg(long, value_name = "path")]
+    pub destination: Option<Utf8PathBuf>,
+
+    /// Report the changes that would be made without making them
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// List the runs which can be restored
+    #[arg(long, conflicts_with_all = ["run", "destination", "dry_run"])]
+    pub list: bool,
+}
+
+/// Copy edits made to a destination back into the nk source it's provisioned
+/// from
+#[derive(Debug, Args)]
+pub struct Adopt {
+    /// Provision info as json
+    #[arg(value_name = "info", value_parser = ProvisionInfo::value_parser)]
+    pub info: ProvisionInfo,
+
+    /// Destination to adopt (a directory adopts everything provisioned
+    /// beneath it)
+    #[arg(value_name = "destination")]
+    pub destination: Utf8PathBuf,
+
+    /// Report the changes that would be made without making them
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// Maximum number of lines shown when diffing adopted files
+    #[arg(long, value_name = "lines", default_value_t = 200)]
+    pub max_diff_lines: usize,
+
+    /// Adopt from under this directory as if it were `/` (ie. an image)
+    #[arg(long, value_name = "path")]
+    pub root: Option<Utf8PathBuf>,
+}
+
+/// Write what provisioning would change to a plan, to review then apply
+#[derive(Debug, Args)]
+pub struct Plan {
+    /// Provision info as json
+    #[arg(value_name = "info", value_parser = ProvisionInfo::value_parser)]
+    pub info: ProvisionInfo,
+
+    /// File to write the plan to
+    #[arg(value_name = "plan")]
+    pub plan: Utf8PathBuf,
+
+    /// Maximum number of lines shown when diffing changed files
+    #[arg(long, value_name = "lines", default_value_t = 200)]
+    pub max_diff_lines: usize,
+
+    /// Plan under this directory as if it were `/` (ie. for an image)
+    #[arg(long, value_name = "path")]
+    pub root: Option<Utf8PathBuf>,
+}
+
+/// Make exactly the changes in a plan, refusing any whose preconditions no
+/// longer hold
+#[derive(Debug, Args)]
+pub struct Apply {
+    /// Plan to apply
+    #[arg(value_name = "plan")]
+    pub plan: Utf8PathBuf,
+
+    /// Check the plan's preconditions and report the changes that would be
+    /// made without making them
+    #[arg(long)]
+    pub dry_run: bool,
 }
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct ProvisionInfo {
     pub sources: Vec<Utf8PathBuf>,
-    // pub vars: serde_json::Map<String, serde_json::Value>,
+    #[serde(default)]
+    pub vars: serde_json::Map<String, serde_json::Value>,
+    /// user to provision for, unless a state sets it's own
+    #[serde(default)]
+    pub user: Option<Owner>,
 }
 
 impl ProvisionInfo {
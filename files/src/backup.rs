@@ -0,0 +1,319 @@
+use crate::change::{symlink_file, Change};
+use anyhow::{anyhow, Context, Result};
+use camino::{Utf8Component, Utf8Path, Utf8PathBuf};
+use serde::{Deserialize, Serialize};
+use std::{
+    cell::RefCell,
+    collections::BTreeSet,
+    fs::{
+        copy, create_dir_all, remove_dir_all, remove_file, rename, File,
+        OpenOptions,
+    },
+    io::{BufRead, BufReader, ErrorKind, Write},
+    time::{Duration, SystemTime},
+};
+use walkdir::WalkDir;
+
+const JOURNAL_FILE_NAME: &str = "journal.jsonl";
+
+/// Where anything a run overwrites or deletes gets moved to
+pub struct Backups {
+    /// NOTE: only created once something is actually backed up
+    run_dir: Utf8PathBuf,
+    /// What's already been (or in a dry run, would be) backed up this run
+    backed_up: RefCell<BTreeSet<Utf8PathBuf>>,
+}
+
+/// What a run did to a destination, so it can be undone
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", tag = "action")]
+pub enum JournalEntry {
+    BackedUp {
+        path: Utf8PathBuf,
+        backup: Utf8PathBuf,
+    },
+    Created {
+        path: Utf8PathBuf,
+    },
+}
+
+impl JournalEntry {
+    pub fn path(&self) -> &Utf8Path {
+        match self {
+            Self::BackedUp { path, .. } | Self::Created { path } => path,
+        }
+    }
+}
+
+impl Backups {
+    pub fn dir() -> Result<Utf8PathBuf> {
+        Ok(crate::plugin_state_dir()?.join("backups"))
+    }
+
+    pub fn new() -> Result<Self> {
+        let dir = Self::dir()?;
+        let timestamp = humantime::format_rfc3339_seconds(SystemTime::now())
+            .to_string()
+            .replace(['-', ':'], "");
+
+        // runs within the same second each get their own directory
+        let mut run_dir = dir.join(&timestamp);
+        let mut suffix = 1;
+        while run_dir.exists() {
+            run_dir = dir.join(format!("{timestamp}-{suffix}"));
+            suffix += 1;
+        }
+
+        Ok(Self {
+            run_dir,
+            backed_up: RefCell::default(),
+        })
+    }
+
+    /// Names of the runs which have backups, oldest first
+    pub fn runs() -> Result<Vec<String>> {
+        let dir = Self::dir()?;
+        if !dir.exists() {
+            return Ok(vec![]);
+        }
+
+        let mut runs = dir
+            .read_dir_utf8()?
+            .map(|entry| Ok(entry?.file_name().to_string()))
+            .collect::<Result<Vec<_>>>()?;
+        runs.sort();
+
+        Ok(runs)
+    }
+
+    /// Everything the given run did, in the order it was done
+    pub fn journal(run: &str) -> Result<Vec<JournalEntry>> {
+        let path = Self::dir()?.join(run).join(JOURNAL_FILE_NAME);
+        if !path.exists() {
+            return Err(anyhow!("{run}: no such backup run"));
+        }
+
+        BufReader::new(File::open(&path)?)
+            .lines()
+            .map(|line| Ok(serde_json::from_str(&line?)?))
+            .collect::<Result<Vec<_>>>()
+            .with_context(|| format!("{path}: failed reading"))
+    }
+
+    /// Record an applied change in this run's journal
+    pub fn record(
+        &self,
+        change: &Change,
+        created: Vec<Utf8PathBuf>,
+    ) -> std::io::Result<()> {
+        let mut entries = created
+            .into_iter()
+            .map(|path| JournalEntry::Created { path })
+            .collect::<Vec<_>>();
+        if let Change::Backup { path, backup } = change {
+            entries.push(JournalEntry::BackedUp {
+                path: path.clone(),
+                backup: backup.clone(),
+            });
+        }
+
+        if entries.is_empty() {
+            return Ok(());
+        }
+
+        // NOTE: appended as we go, so an interrupted run can still be undone
+        // NOTE: backups are only accessible by the user, like what's backed up
+        if let Some(dir) = self.run_dir.parent() {
+            crate::create_state_dir(dir)?;
+        }
+        crate::create_state_dir(&self.run_dir)?;
+        let mut journal = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.run_dir.join(JOURNAL_FILE_NAME))?;
+        for entry in entries {
+            writeln!(journal, "{}", serde_json::to_string(&entry)?)?;
+        }
+
+        Ok(())
+    }
+
+    /// Where a path gets backed up to in this run (mirroring the full path)
+    pub fn path_for(&self, path: &Utf8Path) -> Utf8PathBuf {
+        let mut backup = self.run_dir.clone();
+
+        for component in path.components() {
+            match component {
+                Utf8Component::Prefix(prefix) => backup.push(
+                    prefix.as_str().replace(|c: char| !c.is_alphanumeric(), ""),
+                ),
+                Utf8Component::Normal(name) => backup.push(name),
+                Utf8Component::RootDir
+                | Utf8Component::CurDir
+                | Utf8Component::ParentDir => {}
+            }
+        }
+
+        backup
+    }
+
+    /// Claim a path's backup for this run, returning false if it's already
+    /// been backed up (so what was there originally is kept)
+    pub fn claim(&self, path: &Utf8Path) -> bool {
+        self.backed_up.borrow_mut().insert(path.into())
+    }
+
+    /// Backup runs which are older than the given age (never this run)
+    pub fn expired(&self, max_age: Duration) -> Result<Vec<Utf8PathBuf>> {
+        let dir = Self::dir()?;
+        if !dir.exists() {
+            return Ok(vec![]);
+        }
+
+        let now = SystemTime::now();
+        let mut expired = vec![];
+        for entry in dir.read_dir_utf8()? {
+            let entry = entry?;
+            if entry.path() == self.run_dir {
+                continue;
+            }
+
+            // NOTE: aged by when the run was, as restoring or copying
+            // backups around changes their modification time
+            let Some(time) = run_time(entry.file_name()) else {
+                continue;
+            };
+            if now.duration_since(time).is_ok_and(|age| age > max_age) {
+                expired.push(entry.into_path());
+            }
+        }
+        expired.sort();
+
+        Ok(expired)
+    }
+}
+
+/// When a run was, from it's name (ie. 20231018T120000Z or
+/// 20231018T120000Z-1)
+fn run_time(run: &str) -> Option<SystemTime> {
+    let timestamp = run.split('-').next()?;
+    if timestamp.len() != 16 || !timestamp.is_ascii() {
+        return None;
+    }
+
+    humantime::parse_rfc3339(&format!(
+        "{}-{}-{}T{}:{}:{}Z",
+        &timestamp[0..4],
+        &timestamp[4..6],
+        &timestamp[6..8],
+        &timestamp[9..11],
+        &timestamp[11..13],
+        &timestamp[13..15],
+    ))
+    .ok()
+}
+
+/// Move a file, symlink or directory, falling back to copying when moving
+/// between filesystems (never replacing anything already there)
+pub fn move_path(from: &Utf8Path, to: &Utf8Path) -> std::io::Result<()> {
+    if to.symlink_metadata().is_ok() {
+        return Err(std::io::Error::new(
+            ErrorKind::AlreadyExists,
+            format!("{to}: already exists"),
+        ));
+    }
+    if let Some(parent) = to.parent() {
+        create_dir_all(parent)?;
+    }
+
+    match rename(from, to) {
+        Err(e) if e.kind() == ErrorKind::CrossesDevices => {
+            copy_tree(from, to)?;
+
+            if from.is_dir() && !from.is_symlink() {
+                remove_dir_all(from)
+            } else {
+                remove_file(from)
+            }
+        }
+        result => result,
+    }
+}
+
+/// Copy a file, symlink or directory without following any links
+fn copy_tree(from: &Utf8Path, to: &Utf8Path) -> std::io::Result<()> {
+    for entry in WalkDir::new(from).follow_root_links(false) {
+        let entry = entry?;
+        let relative = entry
+            .path()
+            .strip_prefix(from)
+            .expect("walked paths to be within the walked directory");
+        // NOTE: joining an empty path would add a trailing slash
+        let target = if relative.as_os_str().is_empty() {
+            to.as_std_path().to_path_buf()
+        } else {
+            to.as_std_path().join(relative)
+        };
+
+        if entry.file_type().is_symlink() {
+            symlink_file(std::fs::read_link(entry.path())?, &target)?;
+        } else if entry.file_type().is_dir() {
+            create_dir_all(&target)?;
+        } else {
+            copy(entry.path(), &target)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Backups, JournalEntry};
+    use crate::testing::Sandbox;
+    use serde_json::json;
+
+    #[test]
+    fn keeps_the_first_backup_of_a_path() {
+        let sandbox = Sandbox::new("backup-first");
+        sandbox.write(&sandbox.home("rc"), "original");
+        sandbox.write(&sandbox.source("a"), "a");
+        sandbox.write(&sandbox.source("b"), "b");
+
+        assert!(!sandbox.provision(
+            json!([
+                {
+                    "declaration": "files",
+                    "state": {
+                        "source": "a",
+                        "destination": "~/rc",
+                        "force": true,
+                    },
+                },
+                {
+                    "declaration": "files",
+                    "state": {
+                        "source": "b",
+                        "destination": "~/rc",
+                        "force": true,
+                    },
+                },
+            ]),
+            false,
+        ));
+
+        let runs = Backups::runs().unwrap();
+        assert_eq!(runs.len(), 1);
+        let backups = Backups::journal(&runs[0])
+            .unwrap()
+            .into_iter()
+            .filter_map(|entry| match entry {
+                JournalEntry::BackedUp { backup, .. } => Some(backup),
+                JournalEntry::Created { .. } => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(backups.len(), 1);
+        assert_eq!(sandbox.read(&backups[0]), "original");
+        assert_eq!(sandbox.read(&sandbox.home("rc")), "b");
+    }
+}
@@ -0,0 +1,422 @@
+use crate::options::{
+    deserialize_mode, deserialize_required_mode, serialize_mode,
+    serialize_required_mode,
+};
+use camino::{Utf8Path, Utf8PathBuf};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt::Display,
+    fs::{
+        create_dir_all, hard_link, remove_dir, remove_dir_all, remove_file,
+        rename, File, OpenOptions,
+    },
+    io::Write,
+    path::Path,
+};
+
+/// A single change to the filesystem made while provisioning
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Change {
+    CreateParent {
+        path: Utf8PathBuf,
+        #[serde(serialize_with = "serialize_mode")]
+        #[serde(deserialize_with = "deserialize_mode")]
+        mode: Option<u32>,
+    },
+    CreateDirectory(Utf8PathBuf),
+    DeleteDirectory(Utf8PathBuf),
+    DeleteEmptyDirectory(Utf8PathBuf),
+    DeleteFile(Utf8PathBuf),
+    DeleteSymlink(Utf8PathBuf),
+    Copy {
+        source: Utf8PathBuf,
+        destination: Utf8PathBuf,
+        #[serde(serialize_with = "serialize_mode")]
+        #[serde(deserialize_with = "deserialize_mode")]
+        mode: Option<u32>,
+    },
+    Write {
+        destination: Utf8PathBuf,
+        #[serde(with = "text_or_base64")]
+        contents: Vec<u8>,
+        #[serde(serialize_with = "serialize_mode")]
+        #[serde(deserialize_with = "deserialize_mode")]
+        mode: Option<u32>,
+    },
+    Link {
+        source: Utf8PathBuf,
+        destination: Utf8PathBuf,
+    },
+    HardLink {
+        source: Utf8PathBuf,
+        destination: Utf8PathBuf,
+    },
+    Backup {
+        path: Utf8PathBuf,
+        backup: Utf8PathBuf,
+    },
+    Restore {
+        backup: Utf8PathBuf,
+        path: Utf8PathBuf,
+    },
+    #[cfg(unix)]
+    Chmod {
+        path: Utf8PathBuf,
+        #[serde(serialize_with = "serialize_required_mode")]
+        #[serde(deserialize_with = "deserialize_required_mode")]
+        mode: u32,
+    },
+    #[cfg(unix)]
+    Chown {
+        path: Utf8PathBuf,
+        uid: Option<u32>,
+        gid: Option<u32>,
+    },
+    #[cfg(windows)]
+    Hide(Utf8PathBuf),
+}
+
+impl Change {
+    /// Path this change is made to
+    pub fn path(&self) -> &Utf8Path {
+        match self {
+            Self::CreateParent { path, .. }
+            | Self::CreateDirectory(path)
+            | Self::DeleteDirectory(path)
+            | Self::DeleteEmptyDirectory(path)
+            | Self::DeleteFile(path)
+            | Self::DeleteSymlink(path)
+            | Self::Copy {
+                destination: path, ..
+            }
+            | Self::Write {
+                destination: path, ..
+            }
+            | Self::Link {
+                destination: path, ..
+            }
+            | Self::HardLink {
+                destination: path, ..
+            }
+            | Self::Backup { path, .. }
+            | Self::Restore { path, .. } => path,
+            #[cfg(unix)]
+            Self::Chmod { path, .. } | Self::Chown { path, .. } => path,
+            #[cfg(windows)]
+            Self::Hide(path) => path,
+        }
+    }
+
+    /// Path this change reads from, if any (ie. what's copied)
+    pub fn source(&self) -> Option<&Utf8Path> {
+        match self {
+            Self::Copy { source, .. } | Self::HardLink { source, .. } => {
+                Some(source)
+            }
+            Self::Restore { backup, .. } => Some(backup),
+            _ => None,
+        }
+    }
+
+    /// Paths applying this change would create, which don't exist yet
+    pub fn missing_paths(&self) -> Vec<Utf8PathBuf> {
+        let is_missing = |path: &Utf8Path| !path.is_symlink() && !path.exists();
+
+        match self {
+            // NOTE: includes any missing ancestors, outermost first
+            Self::CreateParent { path, .. } | Self::CreateDirectory(path) => {
+                let mut missing = path
+                    .ancestors()
+                    .take_while(|path| is_missing(path))
+                    .map(Into::into)
+                    .collect::<Vec<_>>();
+                missing.reverse();
+
+                missing
+            }
+            Self::Copy { destination, .. }
+            | Self::Write { destination, .. }
+            | Self::Link { destination, .. }
+            | Self::HardLink { destination, .. }
+            | Self::Restore {
+                path: destination, ..
+            } => {
+                if is_missing(destination) {
+                    vec![destination.clone()]
+                } else {
+                    vec![]
+                }
+            }
+            _ => vec![],
+        }
+    }
+
+    pub fn apply(&self) -> std::io::Result<()> {
+        match self {
+            Self::CreateParent { path, mode } => {
+                create_parents(path, self.missing_paths(), *mode)
+            }
+            Self::CreateDirectory(path) => create_dir_all(path),
+            Self::DeleteDirectory(path) => remove_dir_all(path),
+            Self::DeleteEmptyDirectory(path) => remove_dir(path),
+            Self::DeleteFile(path) | Self::DeleteSymlink(path) => {
+                remove_file(path)
+            }
+            Self::Copy {
+                source,
+                destination,
+                mode,
+            } => write_atomically(destination, *mode, |file| {
+                std::io::copy(&mut File::open(source)?, file).map(|_| ())
+            }),
+            Self::Write {
+                destination,
+                contents,
+                mode,
+            } => write_atomically(destination, *mode, |file| {
+                file.write_all(contents)
+            }),
+            Self::Link {
+                source,
+                destination,
+            } => symlink_file(source, destination),
+            Self::HardLink {
+                source,
+                destination,
+            } => hard_link(source, destination),
+            Self::Backup { path, backup } => {
+                crate::backup::move_path(path, backup)
+            }
+            Self::Restore { backup, path } => {
+                crate::backup::move_path(backup, path)
+            }
+            #[cfg(unix)]
+            Self::Chmod { path, mode } => {
+                use std::fs::{set_permissions, Permissions};
+                use std::os::unix::prelude::PermissionsExt;
+
+                set_permissions(path, Permissions::from_mode(*mode))
+            }
+            // NOTE: doesn't follow symlinks, so links themselves get owned
+            #[cfg(unix)]
+            Self::Chown { path, uid, gid } => {
+                std::os::unix::fs::lchown(path, *uid, *gid)
+            }
+            #[cfg(windows)]
+            Self::Hide(path) => {
+                use windows::Win32::Storage::FileSystem::{
+                    SetFileAttributesW, FILE_ATTRIBUTE_HIDDEN,
+                };
+
+                unsafe {
+                    SetFileAttributesW(
+                        &path.as_os_str().into(),
+                        FILE_ATTRIBUTE_HIDDEN,
+                    )
+                }
+                .map_err(Into::into)
+            }
+        }
+    }
+}
+
+impl Display for Change {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::CreateParent { path, .. } => {
+                write!(f, "create parent directory: {path}")
+            }
+            Self::CreateDirectory(path) => {
+                write!(f, "create directory: {path}")
+            }
+            Self::DeleteDirectory(path) => {
+                write!(f, "delete existing directory: {path}")
+            }
+            Self::DeleteEmptyDirectory(path) => {
+                write!(f, "delete empty directory: {path}")
+            }
+            Self::DeleteFile(path) => write!(f, "delete existing file: {path}"),
+            Self::DeleteSymlink(path) => {
+                write!(f, "delete existing symlink: {path}")
+            }
+            Self::Copy { destination, .. } => {
+                write!(f, "copy file: {destination}")
+            }
+            Self::Write { destination, .. } => {
+                write!(f, "write file: {destination}")
+            }
+            Self::Link { destination, .. } => {
+                write!(f, "link file: {destination}")
+            }
+            Self::HardLink { destination, .. } => {
+                write!(f, "hard link file: {destination}")
+            }
+            Self::Backup { path, backup } => {
+                write!(f, "back up {path} to {backup}")
+            }
+            Self::Restore { backup, path } => {
+                write!(f, "restore {path} from {backup}")
+            }
+            #[cfg(unix)]
+            Self::Chmod { path, mode } => {
+                write!(f, "change permissions to {mode:04o}: {path}")
+            }
+            #[cfg(unix)]
+            Self::Chown { path, uid, gid } => {
+                let id = |id: &Option<u32>| {
+                    id.map(|id| id.to_string()).unwrap_or_default()
+                };
+
+                write!(f, "change ownership to {}:{}: {path}", id(uid), id(gid))
+            }
+            #[cfg(windows)]
+            Self::Hide(path) => write!(f, "hide: {path}"),
+        }
+    }
+}
+
+/// Create missing parent directories with exactly the given permissions,
+/// rather than whatever the process' umask leaves them
+fn create_parents(
+    path: &Utf8Path,
+    missing: Vec<Utf8PathBuf>,
+    mode: Option<u32>,
+) -> std::io::Result<()> {
+    #[cfg(unix)]
+    if let Some(mode) = mode {
+        use std::fs::{set_permissions, DirBuilder, Permissions};
+        use std::os::unix::fs::DirBuilderExt;
+        use std::os::unix::prelude::PermissionsExt;
+
+        // NOTE: outermost first, and the umask applies when creating each
+        // of them, so set the exact mode afterwards
+        for parent in missing {
+            DirBuilder::new().mode(mode).create(&parent)?;
+            set_permissions(&parent, Permissions::from_mode(mode))?;
+        }
+
+        return Ok(());
+    }
+    #[cfg(not(unix))]
+    let _ = (missing, mode);
+
+    create_dir_all(path)
+}
+
+/// Write a file next to the destination then rename it into place, so the
+/// destination is never seen half written or with the wrong permissions
+pub fn write_atomically(
+    destination: &Utf8Path,
+    mode: Option<u32>,
+    write_contents: impl FnOnce(&mut File) -> std::io::Result<()>,
+) -> std::io::Result<()> {
+    let file_name = destination.file_name().unwrap_or_default();
+    let temp_path = destination
+        .with_file_name(format!(".{file_name}.{}.tmp", std::process::id()));
+
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    if let Some(mode) = mode {
+        use std::os::unix::fs::OpenOptionsExt;
+
+        options.mode(mode);
+    }
+    #[cfg(not(unix))]
+    let _ = mode;
+    let mut file = options.open(&temp_path)?;
+
+    let written = (|| {
+        // NOTE: the umask applies when creating the file, so set the exact
+        // mode before anything is written
+        #[cfg(unix)]
+        if let Some(mode) = mode {
+            use std::fs::Permissions;
+            use std::os::unix::prelude::PermissionsExt;
+
+            file.set_permissions(Permissions::from_mode(mode))?;
+        }
+
+        write_contents(&mut file)?;
+        file.sync_all()?;
+
+        rename(&temp_path, destination)
+    })();
+
+    // don't leave partial files lying around
+    if written.is_err() {
+        let _ = remove_file(&temp_path);
+    }
+
+    written
+}
+
+#[cfg(unix)]
+pub fn symlink_file<P: AsRef<Path>, Q: AsRef<Path>>(
+    original: P,
+    link: Q,
+) -> std::io::Result<()> {
+    std::os::unix::fs::symlink(original, link)
+}
+
+#[cfg(windows)]
+pub fn symlink_file<P: AsRef<Path>, Q: AsRef<Path>>(
+    original: P,
+    link: Q,
+) -> std::io::Result<()> {
+    // NOTE: windows needs to know up front whether it's linking a directory
+    let target = match link.as_ref().parent() {
+        Some(parent) => parent.join(original.as_ref()),
+        None => original.as_ref().to_path_buf(),
+    };
+
+    if target.is_dir() {
+        std::os::windows::fs::symlink_dir(original, link)
+    } else {
+        std::os::windows::fs::symlink_file(original, link)
+    }
+}
+
+/// Written contents as text where they are, so planned changes can be
+/// reviewed (falling back to base64 for anything binary)
+mod text_or_base64 {
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+
+    #[derive(Serialize, Deserialize)]
+    #[serde(untagged)]
+    enum Contents {
+        Text(String),
+        Binary { base64: String },
+    }
+
+    pub fn serialize<S>(
+        contents: &[u8],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        std::str::from_utf8(contents)
+            .map_or_else(
+                |_| Contents::Binary {
+                    base64: STANDARD.encode(contents),
+                },
+                |text| Contents::Text(text.into()),
+            )
+            .serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Vec<u8>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        match Contents::deserialize(deserializer)? {
+            Contents::Text(text) => Ok(text.into_bytes()),
+            Contents::Binary { base64 } => STANDARD
+                .decode(&base64)
+                .map_err(|e| D::Error::custom(format!("{e}: invalid base64"))),
+        }
+    }
+}
@@ -0,0 +1,79 @@
+use camino::Utf8Path;
+use similar::TextDiff;
+use std::fs::read;
+
+/// Files larger than this aren't diffed, they're just summarized
+const MAX_DIFF_FILE_SIZE: u64 = 1024 * 1024;
+const TOO_LARGE: &str = "files differ (too large to diff)\n";
+
+/// Unified diff of the changes from `old` to `new`, truncated after
+/// `max_lines` lines
+pub fn unified_diff(
+    old: &Utf8Path,
+    new: &Utf8Path,
+    max_lines: usize,
+) -> std::io::Result<String> {
+    if new.metadata()?.len() > MAX_DIFF_FILE_SIZE {
+        return Ok(TOO_LARGE.into());
+    }
+
+    unified_diff_contents(old, new.as_str(), &read(new)?, max_lines)
+}
+
+/// Unified diff of the changes from `old` to the given contents (ie. a
+/// rendered template), truncated after `max_lines` lines
+pub fn unified_diff_contents(
+    old: &Utf8Path,
+    new_name: &str,
+    new_contents: &[u8],
+    max_lines: usize,
+) -> std::io::Result<String> {
+    if old.metadata()?.len() > MAX_DIFF_FILE_SIZE
+        || new_contents.len() as u64 > MAX_DIFF_FILE_SIZE
+    {
+        return Ok(TOO_LARGE.into());
+    }
+
+    let old_contents = read(old)?;
+
+    let (Some(old_text), Some(new_text)) =
+        (as_text(&old_contents), as_text(new_contents))
+    else {
+        return Ok("binary files differ\n".into());
+    };
+
+    let diff = TextDiff::from_lines(old_text, new_text)
+        .unified_diff()
+        .context_radius(3)
+        .header(old.as_str(), new_name)
+        .to_string();
+
+    Ok(truncate_lines(&diff, max_lines))
+}
+
+/// Contents as text, if they look like text
+fn as_text(contents: &[u8]) -> Option<&str> {
+    if contents.contains(&0) {
+        return None;
+    }
+
+    std::str::from_utf8(contents).ok()
+}
+
+fn truncate_lines(text: &str, max_lines: usize) -> String {
+    let total_lines = text.lines().count();
+    if total_lines <= max_lines {
+        return text.into();
+    }
+
+    let mut truncated = text
+        .split_inclusive('\n')
+        .take(max_lines)
+        .collect::<String>();
+    truncated.push_str(&format!(
+        "... diff truncated ({} more lines)\n",
+        total_lines - max_lines
+    ));
+
+    truncated
+}
@@ -1,56 +1,281 @@
 #![warn(clippy::all, clippy::nursery, clippy::cargo, clippy::single_match_else)]
 #![allow(clippy::cargo_common_metadata)]
 
+mod adopt;
 mod args;
+mod backup;
+mod change;
+mod diff;
+mod manifest;
+mod merge;
+mod options;
+mod ownership;
+mod plan;
+mod prune;
+mod restore;
+mod template;
+#[cfg(test)]
+mod testing;
 
 use anyhow::{anyhow, Result};
-use args::{Arguments, Commands, Provision};
-use camino::{Utf8Path, Utf8PathBuf};
+use args::{Arguments, Commands, Provision, Verify};
+use backup::Backups;
+use camino::{Utf8Component, Utf8Path, Utf8PathBuf};
+use change::Change;
 use clap::Parser;
 use faccess::PathExt;
-use file_id::get_file_id;
-use serde::{de::Error, Deserialize, Deserializer, Serialize};
+use file_id::{get_file_id, FileId};
+use manifest::{hash_contents, hash_file, EntryKind, Manifest, ManifestEntry};
+use merge::Changed;
+use options::{Options, Owner, ParentPolicy};
+use ownership::{Ownership, User};
+use plan::Plan;
+use serde::{Deserialize, Serialize};
 use std::{
-    fs::{copy, create_dir_all, remove_dir_all, remove_file, File},
+    cell::RefCell,
+    collections::BTreeSet,
+    fmt::Display,
+    fs::{DirBuilder, File},
     io::{stdin, Read},
-    path::Path,
-    str::FromStr,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
 };
+use template::Templates;
 use walkdir::WalkDir;
 
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "snake_case", tag = "declaration", content = "state")]
 enum State {
     Files(FileState),
-    Directories(#[serde(deserialize_with = "expand_path")] Utf8PathBuf),
+    Directories(DirectoryState),
+    /// defaults for every later state
+    #[serde(rename = "files.settings")]
+    Settings(Options),
 }
 
 #[derive(Debug, Deserialize)]
 struct FileState {
     source: Utf8PathBuf,
-    #[serde(deserialize_with = "expand_path")]
     destination: Utf8PathBuf,
     #[serde(default)]
     link_files: bool,
+    /// link the source as a whole, rather than each file within it
+    #[serde(default)]
+    link_directories: bool,
+    /// hard link each file, falling back to copying across filesystems
+    #[serde(default)]
+    hardlink: bool,
+    /// only provision files which don't exist yet, leaving the rest to
+    /// whoever edits them
+    #[serde(default, alias = "if_missing")]
+    create_only: bool,
+    /// render the source files as templates
+    #[serde(default)]
+    template: bool,
+    #[serde(flatten)]
+    options: Options,
+}
+
+/// Either just the directory's path, or the path with options
+#[derive(Debug, Deserialize)]
+#[serde(from = "DirectoryStateValue")]
+struct DirectoryState {
+    path: Utf8PathBuf,
+    options: Options,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum DirectoryStateValue {
+    Path(Utf8PathBuf),
+    State {
+        path: Utf8PathBuf,
+        #[serde(flatten)]
+        options: Options,
+    },
+}
+
+impl From<DirectoryStateValue> for DirectoryState {
+    fn from(value: DirectoryStateValue) -> Self {
+        match value {
+            DirectoryStateValue::Path(path) => Self {
+                path,
+                options: Options::default(),
+            },
+            DirectoryStateValue::State { path, options } => {
+                Self { path, options }
+            }
+        }
+    }
+}
+
+/// Apply the settings to a state's options, then expand `~` in it's paths
+/// to the target user's home
+fn resolve_state(
+    context: &Context,
+    path: &mut Utf8PathBuf,
+    options: &mut Options,
+    settings: &Options,
+) -> Result<()> {
+    *options = options.or(settings);
+
+    let root = context.root.as_deref();
+    let home = match User::resolve(options, root)? {
+        Some(user) => Some(user.home),
+        None => dirs::home_dir().map(Utf8PathBuf::try_from).transpose()?,
+    };
+    let expand_path = |path: &Utf8Path| {
+        // TODO: maybe std::path::absolute once stable?
+        let path = Utf8PathBuf::from(
+            shellexpand::tilde_with_context(path.as_str(), || home.as_ref())
+                .as_ref(),
+        );
+
+        match root {
+            Some(root) => with_root(root, &path),
+            None => path,
+        }
+    };
+
+    *path = expand_path(path);
+    if let Some(ParentPolicy::Root(root)) = &mut options.parent_policy {
+        *root = expand_path(root);
+    }
+
+    Ok(())
+}
+
+/// Resolve each state in turn, with the settings before it (settings
+/// themselves are passed along once they're layered in)
+fn resolve_states(
+    context: &Context,
+    states: Vec<State>,
+    user: Option<Owner>,
+) -> impl Iterator<Item = (State, Result<()>)> + '_ {
+    let mut settings = Options {
+        user,
+        ..Options::default()
+    };
+
+    states.into_iter().map(move |mut state| {
+        let resolved = match &mut state {
+            State::Files(state) => resolve_state(
+                context,
+                &mut state.destination,
+                &mut state.options,
+                &settings,
+            ),
+            State::Directories(state) => resolve_state(
+                context,
+                &mut state.path,
+                &mut state.options,
+                &settings,
+            ),
+            State::Settings(options) => {
+                // NOTE: layered, so later settings only override what they set
+                settings = options.or(&settings);
+                Ok(())
+            }
+        };
+
+        (state, resolved)
+    })
+}
+
+/// A state's source within each nk source it exists in
+fn find_sources<'a>(
+    context: &'a Context,
+    state: &FileState,
+) -> Result<Vec<(&'a Utf8Path, Utf8PathBuf)>> {
+    let source = &state.source;
+    let sources = context
+        .nk_sources
+        .iter()
+        .map(|nk_source| (nk_source.as_path(), nk_source.join(source)))
+        .filter(|(_, p)| p.exists())
+        .collect::<Vec<_>>();
+
+    // need at least one source to proceed
+    if sources.is_empty() {
+        return Err(anyhow!("{source}: does not exist"));
+    }
+
+    // check if any sources aren't listable
+    for (_, p) in &sources {
+        if p.is_dir() && !p.as_std_path().executable() {
+            return Err(anyhow!("{p}: is not listable"));
+        }
+    }
+
+    // one link to the whole source, so there can only be the one
+    if state.link_directories && sources.len() > 1 {
+        return Err(anyhow!(
+            "{source}: exists in multiple nk sources, can't link to all of \
+             them"
+        ));
+    }
+
+    Ok(sources)
+}
+
+/// Everything within a source (starting with the source itself), along with
+/// where each is provisioned to
+fn walk_source<'a>(
+    source: &'a Utf8Path,
+    destination: &'a Utf8Path,
+) -> impl Iterator<Item = Result<(Utf8PathBuf, Utf8PathBuf)>> + 'a {
+    WalkDir::new(source)
+        .sort_by_file_name()
+        .into_iter()
+        .map(move |entry| {
+            let source_file = Utf8PathBuf::try_from(entry?.into_path())?;
+            let destination_file = rebase(&source_file, source, destination)?;
+
+            Ok((source_file, destination_file))
+        })
 }
 
-fn expand_path<'de, D>(deserializer: D) -> Result<Utf8PathBuf, D::Error>
-where
-    D: Deserializer<'de>,
-{
-    let path: String = Deserialize::deserialize(deserializer)?;
-    // TODO: maybe std::path::absolute once stable?
-    Utf8PathBuf::from_str(&shellexpand::tilde(&path)).map_err(D::Error::custom)
+/// Move a path from beneath one directory to beneath another (ie. from
+/// within a source to where it's provisioned, or back)
+fn rebase(
+    path: &Utf8Path,
+    from: &Utf8Path,
+    to: &Utf8Path,
+) -> Result<Utf8PathBuf, std::path::StripPrefixError> {
+    let relative = path.strip_prefix(from)?;
+
+    // NOTE: joining an empty path would add a trailing slash
+    Ok(if relative.as_str().is_empty() {
+        to.into()
+    } else {
+        to.join(relative)
+    })
 }
 
 fn main() {
     let args = Arguments::parse();
 
     match args.command {
-        Commands::Provision(args) => provision(args),
+        Commands::Provision(args) => provision(args, false, None),
+        Commands::Verify(args) => verify(args),
+        Commands::Restore(args) => restore::restore(args),
+        Commands::Adopt(args) => adopt::adopt(args),
+        Commands::Plan(args) => plan::plan(args),
+        Commands::Apply(args) => plan::apply(args),
     }
 }
 
+/// Move a path under a root prefix (ie. /etc under /mnt/target is
+/// /mnt/target/etc)
+fn with_root(root: &Utf8Path, path: &Utf8Path) -> Utf8PathBuf {
+    let mut rooted = root.to_path_buf();
+    rooted.extend(path.components().filter(|component| {
+        !matches!(component, Utf8Component::Prefix(_) | Utf8Component::RootDir)
+    }));
+
+    rooted
+}
+
 fn display_path_with_tilde(path: &Utf8Path) -> String {
     let mut path_string = path.to_string();
 
@@ -66,51 +291,294 @@ fn display_path_with_tilde(path: &Utf8Path) -> String {
     path_string
 }
 
+/// Directory the plugin keeps its own state in (ie. the manifest)
+fn plugin_state_dir() -> Result<Utf8PathBuf> {
+    let state_dir = dirs::state_dir()
+        .or_else(dirs::data_local_dir)
+        .ok_or_else(|| anyhow!("could not determine state directory"))?;
+
+    Ok(Utf8PathBuf::try_from(state_dir)?.join("nk-plugins/files"))
+}
+
+/// Create a directory within the plugin's state (and any missing parents),
+/// only accessible by the user as it keeps copies of what's provisioned
+fn create_state_dir(path: &Utf8Path) -> std::io::Result<()> {
+    let mut builder = DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::DirBuilderExt;
+
+        builder.mode(0o700);
+    }
+    builder.create(path)?;
+
+    // NOTE: also tightens directories created before they were kept private
+    #[cfg(unix)]
+    {
+        use std::fs::{set_permissions, Permissions};
+        use std::os::unix::prelude::PermissionsExt;
+
+        set_permissions(path, Permissions::from_mode(0o700))?;
+    }
+
+    Ok(())
+}
+
+/// Report a failure of the run as a whole, rather than of any one state
+fn print_failure(description: &str, output: &str) {
+    print_result(&NkProvisionStateResult {
+        status: NkProvisionStateStatus::Failed,
+        changed: false,
+        description: description.into(),
+        output: output.into(),
+    });
+}
+
+/// Whether any result so far has failed (or conflicted)
+static ANY_FAILED: AtomicBool = AtomicBool::new(false);
+
 fn print_result(result: &NkProvisionStateResult) {
+    if matches!(
+        result.status,
+        NkProvisionStateStatus::Failed | NkProvisionStateStatus::Conflict
+    ) {
+        ANY_FAILED.store(true, Ordering::Relaxed);
+    }
+
     let json = serde_json::to_string(result)
         .expect("state results to not throw errors serializing...");
 
     println!("{json}");
 }
 
-fn provision(args: Provision) {
-    let nk_sources = args.info.sources;
+/// Options shared by every state in a run
+struct Context {
+    /// report changes without making them
+    dry_run: bool,
+    /// fail anything which would've changed
+    verify: bool,
+    /// maximum lines of diff to include in results
+    max_diff_lines: usize,
+    templates: Templates,
+    backups: Backups,
+    /// prefix every destination is provisioned under, as if it were `/`
+    root: Option<Utf8PathBuf>,
+    /// directories the sources of states are found in
+    nk_sources: Vec<Utf8PathBuf>,
+    /// changes recorded to be applied later, rather than made
+    plan: Option<RefCell<Plan>>,
+}
+
+impl Context {
+    /// Context for commands which don't provision states themselves (ie.
+    /// restore), making changes in a backup run of their own
+    fn new(dry_run: bool) -> Result<Self> {
+        Ok(Self {
+            dry_run,
+            verify: false,
+            max_diff_lines: 0,
+            templates: Templates::new(&serde_json::Map::new()),
+            backups: Backups::new()?,
+            // NOTE: paths are used as given, root prefix and all
+            root: None,
+            nk_sources: vec![],
+            plan: None,
+        })
+    }
+
+    /// A path for display, without the root prefix it's provisioned under
+    fn display_path(&self, path: &Utf8Path) -> String {
+        let unrooted = self
+            .root
+            .as_ref()
+            .and_then(|root| path.strip_prefix(root).ok())
+            .map(|path| Utf8Path::new("/").join(path));
+
+        display_path_with_tilde(unrooted.as_deref().unwrap_or(path))
+    }
+}
+
+fn verify(args: Verify) {
+    provision(
+        Provision {
+            info: args.info,
+            dry_run: true,
+            max_diff_lines: args.max_diff_lines,
+            prune: false,
+            backup_retention_days: 0,
+            root: args.root,
+        },
+        true,
+        None,
+    );
+
+    if ANY_FAILED.load(Ordering::Relaxed) {
+        std::process::exit(1);
+    }
+}
 
+fn provision(args: Provision, verify: bool, plan_path: Option<Utf8PathBuf>) {
     let states: Vec<State> = match serde_json::from_reader(stdin()) {
         Ok(v) => v,
         Err(e) => {
             // fallback error handler for the deserialize
-            print_result(&NkProvisionStateResult {
-                status: NkProvisionStateStatus::Failed,
-                changed: false,
-                description: "files".into(),
-                output: format!("{e}: failed deserializing"),
-            });
+            print_failure("files", &format!("{e}: failed deserializing"));
+
+            return;
+        }
+    };
+
+    provision_states(states, args, verify, plan_path);
+}
+
+fn provision_states(
+    states: Vec<State>,
+    args: Provision,
+    verify: bool,
+    plan_path: Option<Utf8PathBuf>,
+) {
+    let mut manifest = match Manifest::load() {
+        Ok(v) => v,
+        Err(e) => {
+            print_failure("files manifest", &format!("{e:#}"));
+
+            return;
+        }
+    };
 
+    let context = match Context::new(args.dry_run) {
+        Ok(context) => Context {
+            verify,
+            max_diff_lines: args.max_diff_lines,
+            templates: Templates::new(&args.info.vars),
+            plan: plan_path
+                .as_ref()
+                .map(|_| RefCell::new(Plan::new(args.root.clone()))),
+            root: args.root,
+            nk_sources: args.info.sources,
+            ..context
+        },
+        Err(e) => {
+            print_failure("files backups", &format!("{e:#}"));
             return;
         }
     };
+    manifest.root.clone_from(&context.root);
+
+    // NOTE: what's declared by a state which couldn't be resolved isn't known
+    // (ie. without the user's home), so nothing can safely be pruned
+    let mut any_unresolved = false;
+
+    for (state, resolved) in resolve_states(&context, states, args.info.user) {
+        any_unresolved |= resolved.is_err();
 
-    for state in states {
         match state {
             State::Files(state) => {
-                if let Err(result) = provision_file(&nk_sources, &state) {
+                if let Err(result) = resolved.and_then(|()| {
+                    provision_file(&context, &mut manifest, &state)
+                }) {
+                    // keep everything we previously provisioned for it
+                    manifest.declare_tree(&state.destination);
+
+                    // fallback error handler for the provision
+                    print_result(&NkProvisionStateResult {
+                        status: NkProvisionStateStatus::Failed,
+                        changed: false,
+                        description: context.display_path(&state.destination),
+                        output: format!("{result:#}"),
+                    });
+                }
+            }
+            State::Directories(state) => {
+                if let Err(result) = resolved {
+                    // keep what we previously provisioned for it
+                    manifest.declare(&state.path);
+
                     // fallback error handler for the provision
                     print_result(&NkProvisionStateResult {
                         status: NkProvisionStateStatus::Failed,
                         changed: false,
-                        description: display_path_with_tilde(
-                            &state.destination,
-                        ),
-                        output: result.to_string(),
+                        description: context.display_path(&state.path),
+                        output: format!("{result:#}"),
                     });
+                } else {
+                    provision_directory(&context, &mut manifest, &state);
                 }
             }
-            State::Directories(destination) => {
-                provision_directory(&destination);
+            State::Settings(_) => {
+                print_result(&NkProvisionStateResult {
+                    status: NkProvisionStateStatus::Success,
+                    changed: false,
+                    description: "files settings".into(),
+                    output: String::new(),
+                });
             }
         };
     }
+
+    if args.prune && any_unresolved {
+        print_failure(
+            "files prune",
+            "skipped, as some states couldn't be resolved",
+        );
+    } else if args.prune {
+        prune::prune(&context, &mut manifest);
+    }
+
+    // NOTE: verifying and planning only care about the states
+    if !context.verify && context.plan.is_none() {
+        expire_backups(&context, args.backup_retention_days);
+    }
+
+    if let (Some(path), Some(plan)) = (plan_path, &context.plan) {
+        // NOTE: a plan is applied as a whole, so it's only worth reviewing
+        // if every state can be provisioned
+        let saved = if ANY_FAILED.load(Ordering::Relaxed) {
+            Err(anyhow!("{path}: not written, as some states would fail"))
+        } else {
+            plan.borrow().save(&path)
+        };
+
+        if let Err(e) = saved {
+            print_failure("files plan", &format!("{e:#}"));
+        }
+    }
+
+    if !context.dry_run {
+        if let Err(e) = manifest.save() {
+            print_failure("files manifest", &format!("{e:#}"));
+        }
+    }
+}
+
+fn expire_backups(context: &Context, retention_days: u64) {
+    let max_age =
+        Duration::from_secs(retention_days.saturating_mul(24 * 60 * 60));
+
+    let expired = match context.backups.expired(max_age) {
+        Ok(v) => v,
+        Err(e) => {
+            print_failure("files backups", &format!("{e:#}"));
+            return;
+        }
+    };
+
+    for backup in expired {
+        let mut result = NkProvisionStateResult {
+            status: NkProvisionStateStatus::Success,
+            changed: false,
+            description: format!(
+                "expire backup {}",
+                context.display_path(&backup)
+            ),
+            output: String::new(),
+        };
+
+        let _ = result.apply_change(context, &Change::DeleteDirectory(backup));
+
+        print_result(&result);
+    }
 }
 
 #[derive(Debug, Serialize, Clone)]
@@ -126,6 +594,8 @@ struct NkProvisionStateResult {
 enum NkProvisionStateStatus {
     Failed,
     Success,
+    /// the destination was edited since it was last provisioned
+    Conflict,
 }
 
 impl NkProvisionStateResult {
@@ -146,6 +616,169 @@ impl NkProvisionStateResult {
         }
     }
 
+    fn apply_change(
+        &mut self,
+        context: &Context,
+        change: &Change,
+    ) -> Result<(), ()> {
+        if context.dry_run {
+            // NOTE: planned changes are made later, as long as what they rely
+            // on hasn't changed by then
+            if let Some(plan) = &context.plan {
+                self.append_check(
+                    plan.borrow_mut()
+                        .record(&self.description, change)
+                        .map_err(|e| {
+                            format!("{e}: failed planning to {change}")
+                        }),
+                )?;
+            }
+
+            self.changed = true;
+            self.output.push_str(&format!("would {change}\n"));
+
+            // anything that would change has drifted from it's source
+            if context.verify {
+                self.status = NkProvisionStateStatus::Failed;
+            }
+
+            return Ok(());
+        }
+
+        let created = change.missing_paths();
+        self.append_change(
+            change
+                .apply()
+                .map_err(|e| format!("{e}: failed to {change}")),
+        )?;
+
+        // keep track of what's been done, so it can be restored later
+        self.append_check(context.backups.record(change, created).map_err(
+            |e| format!("{e}: failed recording in backup journal: {change}"),
+        ))
+    }
+
+    /// Get an existing path out of the way, backing it up first if requested
+    fn remove_existing(
+        &mut self,
+        context: &Context,
+        path: &Utf8Path,
+        backup: bool,
+    ) -> Result<(), ()> {
+        // NOTE: only the first backup of a path in a run is kept, anything
+        // provisioned over it since is just replaced
+        if backup && context.backups.claim(path) {
+            let backup = context.backups.path_for(path);
+            self.apply_change(
+                context,
+                &Change::Backup {
+                    path: path.into(),
+                    backup: backup.clone(),
+                },
+            )?;
+
+            if !context.dry_run {
+                self.output
+                    .push_str(&format!("backed up {path} to {backup}\n"));
+            }
+
+            return Ok(());
+        }
+
+        let change = if path.is_symlink() {
+            Change::DeleteSymlink(path.into())
+        } else if path.is_dir() {
+            Change::DeleteDirectory(path.into())
+        } else {
+            Change::DeleteFile(path.into())
+        };
+
+        self.apply_change(context, &change)
+    }
+
+    /// Delete a directory only if it's (or in a dry run, would be) left empty
+    /// once the given paths are removed
+    fn remove_if_left_empty(
+        &mut self,
+        context: &Context,
+        directory: &Utf8Path,
+        removed: &BTreeSet<Utf8PathBuf>,
+    ) -> Result<(), ()> {
+        let children =
+            self.append_check(directory.read_dir_utf8().map_err(|e| {
+                format!("{e}: failed listing directory: {directory}")
+            }))?;
+        let is_empty = children
+            .flatten()
+            .all(|child| removed.contains(child.path()));
+        if !is_empty {
+            self.output.push_str("not empty, left in place\n");
+            return Ok(());
+        }
+
+        self.apply_change(
+            context,
+            &Change::DeleteEmptyDirectory(directory.into()),
+        )
+    }
+
+    /// Refuse to replace anything which wasn't provisioned (unless forced),
+    /// listing what would be deleted along with it
+    fn check_replaceable(
+        &mut self,
+        context: &Context,
+        manifest: &Manifest,
+        path: &Utf8Path,
+        force: bool,
+    ) -> Result<(), ()> {
+        if force {
+            return Ok(());
+        }
+
+        // NOTE: directories are only ever replaced as a whole, so it's their
+        // contents that matter (empty ones are fine to replace), while links
+        // hold nothing to lose unless they're someone's own link to a
+        // directory (ie. one synced elsewhere)
+        let unmanaged =
+            if path.is_symlink() {
+                if manifest.entries.contains_key(path)
+                    || !path.is_dir()
+                    || is_link_into(path, &context.nk_sources)
+                {
+                    vec![]
+                } else {
+                    vec![path.to_string()]
+                }
+            } else if path.is_dir() {
+                self.append_check(unmanaged_paths(manifest, path).map_err(
+                    |e| format!("{e}: failed listing directory: {path}"),
+                ))?
+            } else if manifest.entries.contains_key(path) {
+                vec![]
+            } else {
+                vec![path.to_string()]
+            };
+
+        if unmanaged.is_empty() {
+            return Ok(());
+        }
+
+        self.append_check(Err(format!(
+            "{path}: would delete what wasn't provisioned, move it out of the \
+             way or set force to replace it anyway:\n{}",
+            unmanaged.join("\n")
+        )))
+    }
+
+    /// Report a conflict, rather than losing local edits
+    fn append_conflict<T>(&mut self, conflict: String) -> Result<T, ()> {
+        self.status = NkProvisionStateStatus::Conflict;
+        self.output.push_str(&conflict);
+        self.output.push('\n');
+
+        Err(())
+    }
+
     fn append_check<T>(&mut self, check: Result<T, String>) -> Result<T, ()> {
         match check {
             Ok(v) => Ok(v),
@@ -158,61 +791,116 @@ impl NkProvisionStateResult {
             }
         }
     }
+
+    /// Record a destination as provisioned from a source file and the nk
+    /// source it was found in (if any)
+    fn record_in_manifest(
+        &mut self,
+        context: &Context,
+        manifest: &mut Manifest,
+        kind: EntryKind,
+        source: Option<(&Utf8Path, &Utf8Path)>,
+        destination: &Utf8Path,
+        source_hash: Option<String>,
+    ) -> Result<(), ()> {
+        if context.dry_run {
+            // NOTE: planned destinations are recorded once they're applied,
+            // as they are by then
+            if let Some(plan) = &context.plan {
+                plan.borrow_mut().record_provisioned(
+                    &self.description,
+                    kind,
+                    source,
+                    destination,
+                    source_hash,
+                );
+            }
+
+            return Ok(());
+        }
+
+        let (source, nk_source) = source.unzip();
+        let mut entry = self.append_check(
+            ManifestEntry::new(kind, source, nk_source, destination).map_err(
+                |e| format!("{e}: failed recording in manifest: {destination}"),
+            ),
+        )?;
+        entry.source_hash = source_hash;
+        manifest.record(destination, entry);
+
+        Ok(())
+    }
 }
 
-fn provision_file(nk_sources: &[Utf8PathBuf], state: &FileState) -> Result<()> {
+fn provision_file(
+    context: &Context,
+    manifest: &mut Manifest,
+    state: &FileState,
+) -> Result<()> {
     let FileState {
-        source,
         destination,
         link_files,
+        link_directories,
+        hardlink,
+        create_only,
+        template,
+        options,
+        ..
     } = state;
-    // find sources
-    let nk_source_relative_sources = nk_sources
-        .iter()
-        .map(|nk_source| nk_source.join(source))
-        .filter(|p| p.exists())
-        .collect::<Vec<_>>();
+    let ownership = Ownership::resolve(options, context.root.as_deref())?;
 
-    // need at least one source to proceed
-    if nk_source_relative_sources.is_empty() {
-        return Err(anyhow!("{source}: does not exist"));
+    // links point at the source itself, so there's nowhere to render to
+    if (*link_files || *link_directories) && *template {
+        return Err(anyhow!(
+            "link_files/link_directories can't be combined with template"
+        ));
+    }
+    if *hardlink && (*link_files || *link_directories || *template) {
+        return Err(anyhow!(
+            "hardlink can't be combined with link_files/link_directories or \
+             template"
+        ));
     }
 
-    // check if any sources aren't listable
-    let nk_source_relative_sources = nk_source_relative_sources
-        .iter()
-        .map(|p| {
-            if p.is_dir() && !p.as_std_path().executable() {
-                return Err(anyhow!("{p}: is not listable"));
-            }
+    let sources = find_sources(context, state)?;
+
+    // one link to the whole source (only ever the one), rather than walking it
+    if *link_directories {
+        for (nk_source, source) in &sources {
+            provision_linked_directory(
+                context,
+                manifest,
+                nk_source,
+                source,
+                destination,
+                options,
+                ownership,
+            );
+        }
 
-            Ok(p)
-        })
-        .collect::<Result<Vec<_>>>()?;
+        return Ok(());
+    }
 
     // walk each source
-    for nk_source_relative_source in nk_source_relative_sources {
-        for entry in WalkDir::new(nk_source_relative_source).sort_by_file_name()
-        {
-            let entry = entry?;
-            let source_file =
-                Utf8PathBuf::from_path_buf(entry.path().into()).unwrap();
-
-            // figure out destination file path
-            let destination_file = if source_file == *nk_source_relative_source
-            {
-                // root of the source
-                destination.clone()
+    for (nk_source, source) in &sources {
+        for entry in walk_source(source, destination) {
+            let (source_file, destination_file) = entry?;
+
+            manifest.declare(&destination_file);
+
+            let kind = if source_file.is_dir() {
+                EntryKind::Directory
+            } else if *link_files {
+                EntryKind::Link
+            } else if *hardlink {
+                EntryKind::HardLink
             } else {
-                // child of the source
-                destination
-                    .join(source_file.strip_prefix(nk_source_relative_source)?)
+                EntryKind::Copy
             };
-
-            let action = if !link_files || source_file.is_dir() {
-                "create"
-            } else {
-                "link"
+            let action = match kind {
+                EntryKind::HardLink => "hardlink",
+                EntryKind::Directory | EntryKind::Copy => "create",
+                EntryKind::Link => "link",
             };
 
             let mut result = NkProvisionStateResult {
@@ -220,20 +908,69 @@ fn provision_file(nk_sources: &[Utf8PathBuf], state: &FileState) -> Result<()> {
                 changed: false,
                 description: format!(
                     "{action} {}",
-                    display_path_with_tilde(&destination_file)
+                    context.display_path(&destination_file)
                 ),
                 output: String::new(),
             };
 
+            // placed once, then left to the app or user (along with any
+            // manifest entry from when it was placed)
+            if *create_only
+                && kind != EntryKind::Directory
+                && (destination_file.is_symlink() || destination_file.exists())
+            {
+                print_result(&result);
+                continue;
+            }
+
+            // NOTE: parents within the source are provisioned as directories
+            // themselves
+            let parents = if source_file == *source {
+                provision_parents(
+                    context,
+                    &mut result,
+                    &destination_file,
+                    options,
+                    ownership,
+                )
+            } else {
+                Ok(())
+            };
+
             // NOTE: result is exclusively used to make it's implementation
             // cleaner (so we can exit if any change fails), all success/failure
             // details are returned through the mutable result
-            let _ = provision_sub_file(
-                &mut result,
-                &source_file,
-                &destination_file,
-                *link_files,
-            );
+            let _ = parents
+                .and_then(|()| {
+                    provision_sub_file(
+                        context,
+                        &mut result,
+                        manifest,
+                        &source_file,
+                        &destination_file,
+                        state,
+                        ownership,
+                    )
+                })
+                .and_then(|source_hash| {
+                    // NOTE: hard links across filesystems are copied instead,
+                    // which are the only ones with contents to track
+                    let kind = match kind {
+                        EntryKind::HardLink if source_hash.is_some() => {
+                            EntryKind::Copy
+                        }
+                        kind => kind,
+                    };
+
+                    result.record_in_manifest(
+                        context,
+                        manifest,
+                        kind,
+                        Some((&source_file, nk_source)),
+                        &destination_file,
+                        source_hash,
+                    )
+                });
 
             print_result(&result);
         }
@@ -242,294 +979,701 @@ fn provision_file(nk_sources: &[Utf8PathBuf], state: &FileState) -> Result<()> {
     Ok(())
 }
 
-fn provision_sub_file(
+/// Create a destination's missing parents, fixing up permissions of those
+/// the parent policy covers
+fn provision_parents(
+    context: &Context,
     result: &mut NkProvisionStateResult,
-    source_file: &Utf8Path,
-    destination_file: &Utf8Path,
-    link_files: bool,
+    destination: &Utf8Path,
+    options: &Options,
+    ownership: Ownership,
 ) -> Result<(), ()> {
+    let Some(destination_parent) = destination.parent() else {
+        return Ok(());
+    };
+
     // create parent directory
-    if let Some(destination_parent) = destination_file.parent() {
-        if !destination_parent.exists() {
-            // create directory
-            result.append_change(
-                create_dir_all(destination_parent).map_err(|e| {
-                    format!("{e}: failed creating parent directory: {destination_parent}")
-                }),
-            )?;
-        }
+    // NOTE: created with the umask's permissions, whichever parents the
+    // policy then fixes up
+    let create_parent = Change::CreateParent {
+        path: destination_parent.into(),
+        mode: options.parent_mode(),
+    };
+    let created = create_parent.missing_paths();
+    if !created.is_empty() {
+        result.apply_change(context, &create_parent)?;
+    }
 
-        // TODO: should support files.settings or something that we can configure a umask with, then configure that first (assuming it'll apply immediately, if not, use it to calculate perms)
-        #[cfg(unix)]
-        {
-            use std::fs::set_permissions;
-            use std::os::unix::prelude::MetadataExt;
-            use std::os::unix::prelude::PermissionsExt;
+    #[cfg(unix)]
+    if let Some(dir_mode) = options.dir_mode(None) {
+        use std::os::unix::prelude::PermissionsExt;
+
+        // NOTE: sorted, so outer directories are fixed up first
+        let parents = match options.parent_policy() {
+            ParentPolicy::None => BTreeSet::new(),
+            ParentPolicy::Created => created.iter().cloned().collect(),
+            ParentPolicy::Root(root) => destination_parent
+                .ancestors()
+                .take_while(|parent| {
+                    parent.starts_with(&root) && *parent != root
+                })
+                .map(Into::into)
+                .chain(created.iter().cloned())
+                .collect::<BTreeSet<Utf8PathBuf>>(),
+        };
 
-            let metadata =
-                destination_parent.metadata().expect("accessing metadata");
-            let mut permissions = metadata.permissions();
-            let existing_mode = permissions.mode() & 0o777;
+        for parent in parents {
+            // NOTE: metadata is missing when the parent was only created in a
+            // dry run, in which case the created directory still needs fixing
+            // up
+            let metadata = parent.metadata().ok();
 
             // chmod parent directory
-            // TODO: uid != 0 is to ensure we don't try to chmod /Users or other system folders... (might be a better way of handling this...)
-            if existing_mode != 0o700 && metadata.uid() != 0 {
-                permissions.set_mode(0o700);
-                result.append_change(
-                    set_permissions(destination_parent, permissions).map_err(
-                        |e| {
-                            format!(
-                                "{e}: failed changing permissions of parent: {destination_parent}",
-                            )
-                        },
-                    ),
+            if metadata.is_none_or(|metadata| {
+                metadata.permissions().mode() & 0o7777 != dir_mode
+            }) {
+                result.apply_change(
+                    context,
+                    &Change::Chmod {
+                        path: parent,
+                        mode: dir_mode,
+                    },
                 )?;
             }
         }
+    }
 
-        #[cfg(windows)]
-        {
-            use std::os::windows::prelude::*;
-            use windows::Win32::Storage::FileSystem::{
-                SetFileAttributesW, FILE_ATTRIBUTE_HIDDEN,
-            };
-
-            // hide dotfiles on windows
-            let file_name = destination_parent.file_name().unwrap_or_default();
-            if file_name.starts_with('.') {
-                let metadata =
-                    destination_parent.metadata().expect("accessing metadata");
-                let attributes = metadata.file_attributes();
-
-                // if not hidden
-                if (attributes & FILE_ATTRIBUTE_HIDDEN.0) == 0 {
-                    // hide
-                    result.append_change(
-                        unsafe {
-                            SetFileAttributesW(
-                                &destination_parent.as_os_str().into(),
-                                FILE_ATTRIBUTE_HIDDEN,
-                            )
-                        }
-                        .map_err(|e| {
-                            format!(
-                                "{e}: failed changing attributes of parent: {destination_parent}",
-                            )
-                        }),
-                    )?;
-                }
-            }
-        }
+    // NOTE: existing parents are left to whoever already owns them
+    for parent in &created {
+        provision_ownership(context, result, parent, ownership)?;
     }
 
-    // create/link
+    #[cfg(windows)]
+    {
+        use std::os::windows::prelude::*;
+        use windows::Win32::Storage::FileSystem::FILE_ATTRIBUTE_HIDDEN;
 
-    if source_file.is_dir() {
+        // hide dotfiles on windows
+        let file_name = destination_parent.file_name().unwrap_or_default();
+        if file_name.starts_with('.') {
+            let metadata = destination_parent.metadata().ok();
+
+            // if not hidden
+            if metadata.as_ref().is_none_or(|metadata| {
+                (metadata.file_attributes() & FILE_ATTRIBUTE_HIDDEN.0) == 0
+            }) {
+                // hide
+                result.apply_change(
+                    context,
+                    &Change::Hide(destination_parent.into()),
+                )?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn provision_sub_file(
+    context: &Context,
+    result: &mut NkProvisionStateResult,
+    manifest: &Manifest,
+    source_file: &Utf8Path,
+    destination_file: &Utf8Path,
+    state: &FileState,
+    ownership: Ownership,
+) -> Result<Option<String>, ()> {
+    let FileState {
+        link_files,
+        hardlink,
+        template,
+        ref options,
+        ..
+    } = *state;
+    let backup = options.backup();
+    let force = options.force();
+
+    // hard links only work within a filesystem, anything else is copied
+    let hardlink = if hardlink && !source_file.is_dir() {
+        let same_device = result.append_check(
+            same_device(source_file, destination_file).map_err(|e| {
+                format!("{e}: failed checking device: {destination_file}")
+            }),
+        )?;
+        if !same_device {
+            result.output.push_str(
+                "source is on a different device, copying instead of hard \
+                 linking\n",
+            );
+        }
+
+        same_device
+    } else {
+        false
+    };
+
+    // NOTE: only copies track what the source provided
+    let mut source_hash = None;
+
+    // create/link
+
+    if source_file.is_dir() {
         // create directory
-        provision_directory_impl(result, destination_file)?;
+        provision_directory_impl(
+            context,
+            result,
+            manifest,
+            Some(source_file),
+            destination_file,
+            options,
+            ownership,
+        )?;
     } else if link_files {
         // link file
 
-        let is_linked_to = result.append_check(
-            is_linked_to(destination_file, source_file).map_err(|e| {
-                format!("{e}: failed checking link: {destination_file}")
-            }),
+        let target = link_target_for(source_file, destination_file, options);
+        let link_state = result.append_check(
+            LinkState::check(context, destination_file, source_file, &target)
+                .map_err(|e| {
+                    format!("{e}: failed checking link: {destination_file}")
+                }),
         )?;
 
-        if !is_linked_to {
+        if !matches!(link_state, LinkState::Linked) {
+            result.description =
+                format!("{} ({link_state})", result.description);
+
             // delete existing first
-            if destination_file.is_dir() {
-                result.append_change(remove_dir_all(destination_file).map_err(|e| format!(
-                    "{e}: failed deleting existing directory: {destination_file}",
-                )))?;
-            } else if destination_file.is_symlink() || destination_file.exists()
-            {
-                result.append_change(remove_file(destination_file).map_err(
-                    |e| {
-                        format!(
-                            "{e}: failed deleting existing file: {destination_file}",
-                        )
-                    },
-                ))?;
+            if destination_file.is_symlink() || destination_file.exists() {
+                result.check_replaceable(
+                    context,
+                    manifest,
+                    destination_file,
+                    force,
+                )?;
+                result.remove_existing(context, destination_file, backup)?;
             }
 
             // link file
-            result.append_change(
-                symlink_file(source_file, destination_file).map_err(|e| {
-                    format!("{e}: failed linking file: {destination_file}")
-                }),
+            result.apply_change(
+                context,
+                &Change::Link {
+                    source: target,
+                    destination: destination_file.into(),
+                },
             )?;
         }
-    } else {
-        // create file
-        let file_matches = result.append_check(
-            file_contents_match(source_file, destination_file).map_err(|e| {
-                format!("{e}: failed linking file: {destination_file}")
+    } else if hardlink {
+        // hard link file
+
+        let is_hardlinked_to = result.append_check(
+            is_hardlinked_to(destination_file, source_file).map_err(|e| {
+                format!("{e}: failed checking hard link: {destination_file}")
             }),
         )?;
 
-        if !file_matches {
+        if !is_hardlinked_to {
             // delete existing first
-            if destination_file.is_dir() {
-                result.append_change(
-                    remove_dir_all(destination_file).map_err(|e| {
-                        format!(
-                            "{e}: failed deleting existing directory: {destination_file}",
-                        )
-                    }),
+            if destination_file.is_symlink() || destination_file.exists() {
+                result.check_replaceable(
+                    context,
+                    manifest,
+                    destination_file,
+                    force,
                 )?;
-            } else if destination_file.is_symlink() {
-                result.append_change(remove_file(destination_file).map_err(
-                    |e| {
-                        format!(
-                            "{e}: failed deleting existing symlink: {destination_file}",
-                        )
-                    },
-                ))?;
+                result.remove_existing(context, destination_file, backup)?;
             }
 
-            // copy file
-            result.append_change(
-                copy(source_file, destination_file).map_err(|e| {
-                    format!("{e}: failed copying file: {destination_file}")
-                }),
+            // hard link file
+            result.apply_change(
+                context,
+                &Change::HardLink {
+                    source: source_file.into(),
+                    destination: destination_file.into(),
+                },
             )?;
         }
 
-        // TODO: should support files.settings or something that we can configure a umask with, then configure that first (assuming it'll apply immediately, if not, use it to calculate perms)
-        #[cfg(unix)]
-        {
-            use std::fs::set_permissions;
-            use std::os::unix::prelude::PermissionsExt;
+        // NOTE: permissions, ownership and attributes are shared with the
+        // source, so changing them would change the source too
+        return Ok(None);
+    } else {
+        // render templates up front, everything else is compared and copied
+        // straight from the source
+        let mut contents = if template {
+            // NOTE: rendered with the username and home of the user it's
+            // provisioned for
+            let user = result.append_check(
+                User::resolve(options, context.root.as_deref())
+                    .map_err(|e| format!("{e:#}")),
+            )?;
 
-            let metadata =
-                destination_file.metadata().expect("accessing metadata");
-            let mut permissions = metadata.permissions();
-            let existing_mode = permissions.mode() & 0o777;
+            Contents::Rendered(
+                result.append_check(
+                    context
+                        .templates
+                        .render(source_file, user.as_ref())
+                        .map_err(|e| {
+                            format!(
+                            "{e:#}: failed rendering template: {source_file}"
+                        )
+                        }),
+                )?,
+            )
+        } else {
+            Contents::Source
+        };
 
-            // determine perms to set
-            let perms = if source_file.as_std_path().executable() {
-                0o700
-            } else {
-                0o600
-            };
+        // determine perms to set
+        let mode = options.file_mode(source_file);
+
+        // NOTE: tracked separately to the destination, as they differ once
+        // local edits are merged in
+        let hash =
+            result.append_check(contents.hash(source_file).map_err(|e| {
+                format!("{e}: failed hashing file: {source_file}")
+            }))?;
+
+        // keep what's provisioned, to merge against once both the source and
+        // destination change (including what's planned, for once it's applied)
+        if !context.dry_run || context.plan.is_some() {
+            result.append_check(
+                merge::keep_base(&contents, source_file, &hash)
+                    .map_err(|e| format!("{e:#}")),
+            )?;
+        }
+
+        // create file
+        let file_matches = result.append_check(
+            contents
+                .matches(source_file, destination_file)
+                .map_err(|e| {
+                    format!("{e}: failed comparing file: {destination_file}")
+                }),
+        )?;
 
-            // chmod file
-            if existing_mode != perms {
-                permissions.set_mode(perms);
-                result.append_change(
-                    set_permissions(destination_file, permissions).map_err(
-                        |e| {
+        // what changed since it was last provisioned
+        let changed = if file_matches {
+            None
+        } else {
+            result.append_check(
+                Changed::since_provisioned(manifest, destination_file, &hash)
+                    .map_err(|e| {
+                        format!("{e}: failed hashing file: {destination_file}")
+                    }),
+            )?
+        };
+
+        // NOTE: local edits merged in last time leave it differing from the
+        // source, with nothing new to provision
+        let file_matches = file_matches
+            || changed
+                .as_ref()
+                .is_some_and(|changed| !changed.destination && !changed.source);
+        source_hash = Some(hash);
+
+        if !file_matches {
+            // NOTE: edited locally (now or merged in before), so the source
+            // can't simply replace it
+            if let Some(changed) = changed
+                .filter(|changed| changed.local_edits && !options.overwrite())
+            {
+                contents = merge::merge_edits(
+                    context,
+                    result,
+                    source_file,
+                    destination_file,
+                    &contents,
+                    &changed,
+                )?;
+            }
+
+            // show what's changing in the existing file
+            if destination_file.is_file() {
+                let diff = result.append_check(
+                    contents
+                        .diff(
+                            source_file,
+                            destination_file,
+                            context.max_diff_lines,
+                        )
+                        .map_err(|e| {
                             format!(
-                                "{e}: failed changing permissions of file: {destination_file}",
+                                "{e}: failed diffing file: {destination_file}"
                             )
-                        },
-                    ),
+                        }),
                 )?;
+                result.output.push_str(&diff);
+            }
+
+            if destination_file.is_symlink() || destination_file.exists() {
+                result.check_replaceable(
+                    context,
+                    manifest,
+                    destination_file,
+                    force,
+                )?;
+            }
+
+            // delete existing first (files are otherwise just replaced)
+            if destination_file.is_symlink()
+                || destination_file.is_dir()
+                || (backup && destination_file.exists())
+            {
+                result.remove_existing(context, destination_file, backup)?;
+            }
+
+            // copy file (with it's permissions already set)
+            result.apply_change(
+                context,
+                &contents.into_change(source_file, destination_file, mode),
+            )?;
+        } else {
+            #[cfg(unix)]
+            if let Some(mode) = mode {
+                use std::os::unix::prelude::PermissionsExt;
+
+                let metadata =
+                    destination_file.metadata().expect("accessing metadata");
+                let existing_mode = metadata.permissions().mode() & 0o7777;
+
+                // chmod file
+                if existing_mode != mode {
+                    result.apply_change(
+                        context,
+                        &Change::Chmod {
+                            path: destination_file.into(),
+                            mode,
+                        },
+                    )?;
+                }
             }
         }
     }
 
+    // NOTE: directories are owned as they're provisioned
+    if !source_file.is_dir() {
+        provision_ownership(context, result, destination_file, ownership)?;
+    }
+
     #[cfg(windows)]
     {
         use std::os::windows::prelude::*;
-        use windows::Win32::Storage::FileSystem::{
-            SetFileAttributesW, FILE_ATTRIBUTE_HIDDEN,
-        };
+        use windows::Win32::Storage::FileSystem::FILE_ATTRIBUTE_HIDDEN;
 
         // hide dotfiles on windows
         let file_name = destination_file.file_name().unwrap_or_default();
         if file_name.starts_with('.') {
-            let metadata =
-                destination_file.metadata().expect("accessing metadata");
-            let attributes = metadata.file_attributes();
+            let metadata = destination_file.metadata().ok();
 
             // if not hidden
-            if (attributes & FILE_ATTRIBUTE_HIDDEN.0) == 0 {
+            if metadata.as_ref().is_none_or(|metadata| {
+                (metadata.file_attributes() & FILE_ATTRIBUTE_HIDDEN.0) == 0
+            }) {
                 // hide
-                result.append_change(
-                    unsafe {
-                        SetFileAttributesW(
-                            &destination_file.as_os_str().into(),
-                            FILE_ATTRIBUTE_HIDDEN,
-                        )
-                    }
-                    .map_err(|e| {
-                        format!(
-                            "{e}: failed changing attributes of directory: {destination_file}",
-                        )
-                    }),
+                result.apply_change(
+                    context,
+                    &Change::Hide(destination_file.into()),
                 )?;
             }
         }
     }
 
-    Ok(())
+    Ok(source_hash)
 }
 
-fn provision_directory(destination: &Utf8Path) {
+fn provision_directory(
+    context: &Context,
+    manifest: &mut Manifest,
+    state: &DirectoryState,
+) {
+    let DirectoryState {
+        path: destination,
+        options,
+    } = state;
+
     let mut result = NkProvisionStateResult {
         status: NkProvisionStateStatus::Success,
         changed: false,
-        description: format!("create {}", display_path_with_tilde(destination)),
+        description: format!("create {}", context.display_path(destination)),
         output: String::new(),
     };
 
+    manifest.declare(destination);
+
     // NOTE: result is exclusively used to make it's implementation
     // cleaner (so we can exit if any change fails), all success/failure
     // details are returned through the mutable result
-    let _ = provision_directory_impl(&mut result, destination);
+    let _ = result
+        .append_check(
+            Ownership::resolve(options, context.root.as_deref())
+                .map_err(|e| format!("{e:#}")),
+        )
+        .and_then(|ownership| {
+            provision_parents(
+                context,
+                &mut result,
+                destination,
+                options,
+                ownership,
+            )?;
+            provision_directory_impl(
+                context,
+                &mut result,
+                manifest,
+                None,
+                destination,
+                options,
+                ownership,
+            )
+        })
+        .and_then(|()| {
+            result.record_in_manifest(
+                context,
+                manifest,
+                EntryKind::Directory,
+                None,
+                destination,
+                None,
+            )
+        });
 
     print_result(&result);
 }
 
+fn provision_linked_directory(
+    context: &Context,
+    manifest: &mut Manifest,
+    nk_source: &Utf8Path,
+    source: &Utf8Path,
+    destination: &Utf8Path,
+    options: &Options,
+    ownership: Ownership,
+) {
+    let mut result = NkProvisionStateResult {
+        status: NkProvisionStateStatus::Success,
+        changed: false,
+        description: format!("link {}", context.display_path(destination)),
+        output: String::new(),
+    };
+
+    manifest.declare(destination);
+
+    // NOTE: result is exclusively used to make it's implementation
+    // cleaner (so we can exit if any change fails), all success/failure
+    // details are returned through the mutable result
+    let _ = provision_parents(
+        context,
+        &mut result,
+        destination,
+        options,
+        ownership,
+    )
+    .and_then(|()| {
+        let target = link_target_for(source, destination, options);
+        let link_state = result.append_check(
+            LinkState::check(context, destination, source, &target).map_err(
+                |e| format!("{e}: failed checking link: {destination}"),
+            ),
+        )?;
+
+        if !matches!(link_state, LinkState::Linked) {
+            result.description =
+                format!("{} ({link_state})", result.description);
+
+            // delete existing first
+            if destination.is_symlink() || destination.exists() {
+                result.check_replaceable(
+                    context,
+                    manifest,
+                    destination,
+                    options.force(),
+                )?;
+                result.remove_existing(
+                    context,
+                    destination,
+                    options.backup(),
+                )?;
+            }
+
+            // link directory
+            result.apply_change(
+                context,
+                &Change::Link {
+                    source: target,
+                    destination: destination.into(),
+                },
+            )?;
+        }
+
+        provision_ownership(context, &mut result, destination, ownership)
+    })
+    .and_then(|()| {
+        result.record_in_manifest(
+            context,
+            manifest,
+            EntryKind::Link,
+            Some((source, nk_source)),
+            destination,
+            None,
+        )
+    });
+
+    print_result(&result);
+}
+
+/// Files within a directory which the manifest doesn't know about
+fn unmanaged_paths(
+    manifest: &Manifest,
+    directory: &Utf8Path,
+) -> walkdir::Result<Vec<String>> {
+    let mut unmanaged = vec![];
+
+    for entry in WalkDir::new(directory).min_depth(1).sort_by_file_name() {
+        let entry = entry?;
+        let path = Utf8Path::from_path(entry.path()).unwrap_or(directory);
+
+        // NOTE: links and (what'd be left) empty directories hold nothing to
+        // lose
+        if entry.file_type().is_file() && !manifest.entries.contains_key(path) {
+            unmanaged.push(path.to_string());
+        }
+    }
+
+    Ok(unmanaged)
+}
+
 // TODO: rename...
 fn provision_directory_impl(
+    context: &Context,
     result: &mut NkProvisionStateResult,
+    manifest: &Manifest,
+    source: Option<&Utf8Path>,
     destination: &Utf8Path,
+    options: &Options,
+    ownership: Ownership,
 ) -> Result<(), ()> {
-    if !destination.is_dir() {
+    // NOTE: a link to a directory isn't one, and changing it would change
+    // whatever it points at
+    if destination.is_symlink() || !destination.is_dir() {
         // delete existing first
-        if destination.exists() {
-            result.append_change(remove_file(destination).map_err(|e| {
-                format!("{e}: failed deleting existing file: {destination}")
-            }))?;
+        if destination.is_symlink() || destination.exists() {
+            result.check_replaceable(
+                context,
+                manifest,
+                destination,
+                options.force(),
+            )?;
+            result.remove_existing(context, destination, options.backup())?;
         }
 
         // create directory
-        result.append_change(create_dir_all(destination).map_err(|e| {
-            format!("{e}: failed creating directory: {destination}")
-        }))?;
+        result.apply_change(
+            context,
+            &Change::CreateDirectory(destination.into()),
+        )?;
     }
 
-    // TODO: should support files.settings or something that we can configure a umask with, then configure that first (assuming it'll apply immediately, if not, use it to calculate perms)
     #[cfg(unix)]
-    {
-        use std::fs::set_permissions;
+    if let Some(dir_mode) = options.dir_mode(source) {
         use std::os::unix::prelude::PermissionsExt;
 
-        let metadata = destination.metadata().expect("accessing metadata");
-        let mut permissions = metadata.permissions();
-        let existing_mode = permissions.mode() & 0o777;
+        // NOTE: metadata is missing when the directory was only created in a
+        // dry run
+        let metadata = destination.metadata().ok();
 
         // chmod directory
-        if existing_mode != 0o700 {
-            permissions.set_mode(0o700);
-            result.append_change(
-                set_permissions(destination, permissions).map_err(
-                    |e| {
-                        format!(
-                            "{e}: failed changing permissions of directory: {destination}",
-                        )
-                    },
-                ),
+        if metadata.is_none_or(|metadata| {
+            metadata.permissions().mode() & 0o7777 != dir_mode
+        }) {
+            result.apply_change(
+                context,
+                &Change::Chmod {
+                    path: destination.into(),
+                    mode: dir_mode,
+                },
             )?;
         }
     }
 
+    provision_ownership(context, result, destination, ownership)
+}
+
+/// Change who owns a path, if it isn't already owned as requested
+fn provision_ownership(
+    context: &Context,
+    result: &mut NkProvisionStateResult,
+    path: &Utf8Path,
+    ownership: Ownership,
+) -> Result<(), ()> {
+    if ownership.is_empty() {
+        return Ok(());
+    }
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::prelude::MetadataExt;
+
+        // NOTE: metadata is missing when the path was only created in a dry
+        // run
+        let metadata = path.symlink_metadata().ok();
+
+        // chown path
+        if metadata.is_none_or(|metadata| {
+            ownership.differs(metadata.uid(), metadata.gid())
+        }) {
+            result.apply_change(
+                context,
+                &Change::Chown {
+                    path: path.into(),
+                    uid: ownership.uid,
+                    gid: ownership.gid,
+                },
+            )?;
+        }
+    }
+
+    #[cfg(not(unix))]
+    let _ = (context, result, path);
+
     Ok(())
 }
 
+/// Where a symlink points, relative targets are resolved against the link's
+/// directory
+fn link_target(link: &Utf8Path) -> std::io::Result<Utf8PathBuf> {
+    let target = link.read_link_utf8()?;
+
+    let Some(parent) = link.parent().filter(|_| target.is_relative()) else {
+        return Ok(target);
+    };
+
+    // NOTE: the same way relative links are made, so ../ lands in the same
+    // place
+    let parent = parent.canonicalize_utf8().unwrap_or_else(|_| parent.into());
+    let mut resolved = Utf8PathBuf::new();
+    for component in parent.join(target).components() {
+        match component {
+            Utf8Component::ParentDir => {
+                resolved.pop();
+            }
+            Utf8Component::CurDir => {}
+            component => resolved.push(component),
+        }
+    }
+
+    Ok(resolved)
+}
+
+/// Whether a link points within any of the nk sources
+fn is_link_into(link: &Utf8Path, nk_sources: &[Utf8PathBuf]) -> bool {
+    link_target(link).is_ok_and(|target| {
+        nk_sources
+            .iter()
+            .any(|nk_source| target.starts_with(nk_source))
+    })
+}
+
 fn is_linked_to(
     destination_file: &Utf8Path,
     source_file: &Utf8Path,
@@ -545,60 +1689,394 @@ fn is_linked_to(
     Ok(destination_file_id == source_file_id)
 }
 
-#[cfg(unix)]
-fn symlink_file<P: AsRef<Path>, Q: AsRef<Path>>(
-    original: P,
-    link: Q,
-) -> std::io::Result<()> {
-    std::os::unix::fs::symlink(original, link)
+/// What's at a link's destination, compared to the link it should be
+enum LinkState {
+    /// linked to the source as requested
+    Linked,
+    /// nothing there yet
+    Missing,
+    /// a file or directory rather than a link
+    NotALink,
+    /// a link to something which doesn't exist
+    Broken(Utf8PathBuf),
+    /// a link into an nk source other than the one the source is in
+    OtherNkSource(Utf8PathBuf),
+    /// a link to somewhere outside every nk source
+    OutsideNkSources(Utf8PathBuf),
+    /// a link elsewhere in the source's nk source (or in another style)
+    Elsewhere(Utf8PathBuf),
+}
+
+impl LinkState {
+    /// NOTE: only ever reads the link, never follows it to change anything
+    fn check(
+        context: &Context,
+        destination: &Utf8Path,
+        source: &Utf8Path,
+        target: &Utf8Path,
+    ) -> std::io::Result<Self> {
+        if !destination.is_symlink() {
+            return Ok(if destination.exists() {
+                Self::NotALink
+            } else {
+                Self::Missing
+            });
+        }
+
+        if is_linked_as(destination, source, target)? {
+            return Ok(Self::Linked);
+        }
+
+        let current = link_target(destination)?;
+        if !destination.exists() {
+            return Ok(Self::Broken(current));
+        }
+
+        let Some(nk_source) = context
+            .nk_sources
+            .iter()
+            .find(|nk_source| current.starts_with(nk_source))
+        else {
+            return Ok(Self::OutsideNkSources(current));
+        };
+
+        Ok(if source.starts_with(nk_source) {
+            Self::Elsewhere(current)
+        } else {
+            Self::OtherNkSource(current)
+        })
+    }
+}
+
+impl Display for LinkState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Linked => write!(f, "linked"),
+            Self::Missing => write!(f, "missing"),
+            Self::NotALink => write!(f, "not a link"),
+            Self::Broken(target) => write!(f, "broken link to {target}"),
+            Self::OtherNkSource(target) => {
+                write!(f, "linked to a different nk source: {target}")
+            }
+            Self::OutsideNkSources(target) => {
+                write!(f, "linked outside the nk sources: {target}")
+            }
+            Self::Elsewhere(target) => write!(f, "linked to {target}"),
+        }
+    }
+}
+
+/// Whether a destination is the same file as the source, without being a
+/// symlink to it
+fn is_hardlinked_to(
+    destination_file: &Utf8Path,
+    source_file: &Utf8Path,
+) -> std::io::Result<bool> {
+    Ok(!destination_file.is_symlink()
+        && is_linked_to(destination_file, source_file)?)
+}
+
+/// Whether the source is on the same filesystem the destination would be
+/// created in
+fn same_device(
+    source_file: &Utf8Path,
+    destination_file: &Utf8Path,
+) -> std::io::Result<bool> {
+    // NOTE: the destination's directory might not exist yet (ie. in a dry
+    // run), in which case it'd be created on the nearest existing one's
+    let Some(existing) = destination_file
+        .ancestors()
+        .skip(1)
+        .find(|ancestor| ancestor.exists())
+    else {
+        return Ok(false);
+    };
+
+    let device = |id: FileId| match id {
+        FileId::Inode { device_id, .. } => device_id,
+        FileId::LowRes {
+            volume_serial_number,
+            ..
+        } => volume_serial_number.into(),
+        FileId::HighRes {
+            volume_serial_number,
+            ..
+        } => volume_serial_number,
+    };
+
+    Ok(device(get_file_id(source_file)?) == device(get_file_id(existing)?))
+}
+
+/// Whether a destination is linked to the source with exactly the given
+/// target (ie. in the requested relative/absolute style)
+fn is_linked_as(
+    destination_file: &Utf8Path,
+    source_file: &Utf8Path,
+    target: &Utf8Path,
+) -> std::io::Result<bool> {
+    Ok(is_linked_to(destination_file, source_file)?
+        && destination_file
+            .read_link_utf8()
+            .is_ok_and(|existing| existing == target))
 }
 
-#[cfg(windows)]
-fn symlink_file<P: AsRef<Path>, Q: AsRef<Path>>(
-    original: P,
-    link: Q,
-) -> std::io::Result<()> {
-    std::os::windows::fs::symlink_file(original, link)
+/// What a link to the source should point at, relative to the destination's
+/// directory when requested
+fn link_target_for(
+    source_file: &Utf8Path,
+    destination_file: &Utf8Path,
+    options: &Options,
+) -> Utf8PathBuf {
+    let Some(destination_parent) = destination_file.parent() else {
+        return source_file.into();
+    };
+    if !options.relative_links() {
+        return source_file.into();
+    }
+
+    // NOTE: resolved where possible, so links through symlinked directories
+    // still land on the source
+    let canonical = |path: &Utf8Path| {
+        path.canonicalize_utf8().unwrap_or_else(|_| path.into())
+    };
+
+    relative_path(&canonical(destination_parent), &canonical(source_file))
+}
+
+/// Path to `to` from the directory `from`, both being absolute
+fn relative_path(from: &Utf8Path, to: &Utf8Path) -> Utf8PathBuf {
+    let from = from.components().collect::<Vec<_>>();
+    let to = to.components().collect::<Vec<_>>();
+
+    let common = from.iter().zip(&to).take_while(|(a, b)| a == b).count();
+    // ie. on different drives
+    if common == 0 {
+        return to.iter().collect();
+    }
+
+    from[common..]
+        .iter()
+        .map(|_| Utf8Component::ParentDir)
+        .chain(to[common..].iter().copied())
+        .collect()
+}
+
+/// What a copied destination file's contents come from
+enum Contents {
+    /// copied straight from the source file
+    Source,
+    /// rendered from the source file
+    Rendered(Vec<u8>),
+    /// local edits merged with the source file's changes
+    Merged(Vec<u8>),
+}
+
+impl Contents {
+    fn hash(&self, source: &Utf8Path) -> std::io::Result<String> {
+        match self {
+            Self::Source => hash_file(source),
+            Self::Rendered(contents) | Self::Merged(contents) => {
+                Ok(hash_contents(contents))
+            }
+        }
+    }
+
+    /// These contents, if they're text small enough to merge
+    fn text(&self, source: &Utf8Path) -> std::io::Result<Option<String>> {
+        match self {
+            Self::Source => merge::read_text(source),
+            Self::Rendered(contents) | Self::Merged(contents) => {
+                Ok(merge::as_text(contents.clone()))
+            }
+        }
+    }
+
+    fn matches(
+        &self,
+        source: &Utf8Path,
+        destination: &Utf8Path,
+    ) -> std::io::Result<bool> {
+        match self {
+            Self::Source => file_contents_match(source, destination),
+            Self::Rendered(contents) | Self::Merged(contents) => {
+                contents_match(
+                    contents.as_slice(),
+                    contents.len() as u64,
+                    destination,
+                )
+            }
+        }
+    }
+
+    fn diff(
+        &self,
+        source: &Utf8Path,
+        destination: &Utf8Path,
+        max_lines: usize,
+    ) -> std::io::Result<String> {
+        match self {
+            Self::Source => diff::unified_diff(destination, source, max_lines),
+            Self::Rendered(contents) | Self::Merged(contents) => {
+                diff::unified_diff_contents(
+                    destination,
+                    source.as_str(),
+                    contents,
+                    max_lines,
+                )
+            }
+        }
+    }
+
+    fn into_change(
+        self,
+        source: &Utf8Path,
+        destination: &Utf8Path,
+        mode: Option<u32>,
+    ) -> Change {
+        match self {
+            Self::Source => Change::Copy {
+                source: source.into(),
+                destination: destination.into(),
+                mode,
+            },
+            Self::Rendered(contents) | Self::Merged(contents) => {
+                Change::Write {
+                    destination: destination.into(),
+                    contents,
+                    mode,
+                }
+            }
+        }
+    }
 }
 
 fn file_contents_match(
     source: &Utf8Path,
     destination: &Utf8Path,
 ) -> std::io::Result<bool> {
-    if !destination.exists() || destination.is_dir() {
+    let source_file = File::open(source)?;
+    let source_len = source_file.metadata()?.len();
+
+    contents_match(source_file, source_len, destination)
+}
+
+fn contents_match(
+    mut source: impl Read,
+    source_len: u64,
+    destination: &Utf8Path,
+) -> std::io::Result<bool> {
+    // NOTE: links get replaced by a copy, rather than compared through
+    if destination.is_symlink() || !destination.exists() || destination.is_dir()
+    {
         return Ok(false);
     }
 
-    let mut source_file = File::open(source)?;
     let mut destination_file = File::open(destination)?;
 
     // check file size
-    if source_file.metadata()?.len() != destination_file.metadata()?.len() {
+    if source_len != destination_file.metadata()?.len() {
         return Ok(false);
     }
 
-    // check file contents
-    let mut source_contents = String::new();
-    let mut destination_contents = String::new();
+    // check file contents (a chunk at a time, so file size doesn't matter)
+    const CHUNK_SIZE: usize = 64 * 1024;
+    let mut source_buffer = vec![0u8; CHUNK_SIZE];
+    let mut destination_buffer = vec![0u8; CHUNK_SIZE];
 
-    source_file.read_to_string(&mut source_contents)?;
-    destination_file.read_to_string(&mut destination_contents)?;
+    loop {
+        let source_read = read_chunk(&mut source, &mut source_buffer)?;
+        let destination_read =
+            read_chunk(&mut destination_file, &mut destination_buffer)?;
 
-    if source_contents != destination_contents {
-        return Ok(false);
+        if source_buffer[..source_read]
+            != destination_buffer[..destination_read]
+        {
+            return Ok(false);
+        }
+
+        if source_read == 0 {
+            return Ok(true);
+        }
     }
+}
 
-    // TODO: allow this to handle large files (maybe have a size limit where it still does the simple thing of loading the whole thing? since that's likely fairly fast...)
-    // // check file contents
-    // let mut source_buf_reader = BufReader::new(source_file);
-    // let mut destination_buf_reader = BufReader::new(destination_file);
+/// Fill as much of the buffer as possible, only returning less than a full
+/// buffer at the end of the file
+fn read_chunk(
+    file: &mut impl Read,
+    buffer: &mut [u8],
+) -> std::io::Result<usize> {
+    let mut total = 0;
+
+    while total < buffer.len() {
+        match file.read(&mut buffer[total..]) {
+            Ok(0) => break,
+            Ok(read) => total += read,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
 
-    // const SIZE: usize = 8192;
-    // let mut source_buffer = [0u8; SIZE];
-    // let mut destination_buffer = [0u8; SIZE];
+    Ok(total)
+}
 
-    // source_buf_reader.read_exact(&mut source_buffer)?;
-    // destination_buf_reader.read_exact(&mut destination_buffer)?;
+#[cfg(test)]
+mod tests {
+    use crate::testing::Sandbox;
+    use serde_json::json;
 
-    Ok(true)
+    #[test]
+    #[cfg(unix)]
+    fn keeps_unmanaged_links_to_directories() {
+        let sandbox = Sandbox::new("unmanaged-link");
+        sandbox.write(&sandbox.home("synced/config/rc"), "synced");
+        sandbox.write(&sandbox.source("config/rc"), "provisioned");
+        std::os::unix::fs::symlink(
+            sandbox.home("synced/config"),
+            sandbox.home(".config"),
+        )
+        .unwrap();
+
+        assert!(sandbox.provision(
+            json!([{
+                "declaration": "files",
+                "state": {
+                    "source": "config",
+                    "destination": "~/.config",
+                    "link_directories": true,
+                },
+            }]),
+            false,
+        ));
+
+        assert!(sandbox.home(".config").is_symlink());
+        assert_eq!(sandbox.read(&sandbox.home(".config/rc")), "synced");
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn creates_parents_with_the_umask() {
+        use std::os::unix::prelude::PermissionsExt;
+
+        let sandbox = Sandbox::new("parent-umask");
+        sandbox.write(&sandbox.source("rc"), "rc");
+
+        assert!(!sandbox.provision(
+            json!([{
+                "declaration": "files",
+                "state": {
+                    "source": "rc",
+                    "destination": "~/a/b/rc",
+                    "umask": "0027",
+                    "parent_policy": "none",
+                },
+            }]),
+            false,
+        ));
+
+        for parent in ["a", "a/b"] {
+            let metadata = sandbox.home(parent).metadata().unwrap();
+            assert_eq!(metadata.permissions().mode() & 0o7777, 0o750);
+        }
+    }
 }
@@ -0,0 +1,308 @@
+use crate::{
+    change::write_atomically,
+    options::{deserialize_mode, serialize_mode},
+};
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::{remove_file, rename, write, File},
+    io::{ErrorKind, Write},
+};
+
+const MANIFEST_VERSION: u32 = 1;
+
+/// Record of everything the plugin has provisioned, keyed by destination
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Manifest {
+    pub version: u32,
+    pub entries: BTreeMap<Utf8PathBuf, ManifestEntry>,
+    /// destinations declared by the states in this run
+    #[serde(skip)]
+    declared: BTreeSet<Utf8PathBuf>,
+    /// root prefix of this run, only it's own entries are considered
+    /// undeclared
+    #[serde(skip)]
+    pub root: Option<Utf8PathBuf>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    pub kind: EntryKind,
+    /// file the destination was provisioned from
+    pub source: Option<Utf8PathBuf>,
+    /// nk source the file was found in
+    pub nk_source: Option<Utf8PathBuf>,
+    /// NOTE: written the same way as modes in plans (ie. "0644"), older
+    /// manifests' plain numbers are still read
+    #[serde(default)]
+    #[serde(serialize_with = "serialize_mode")]
+    #[serde(deserialize_with = "deserialize_mode")]
+    pub mode: Option<u32>,
+    /// sha256 of the provisioned contents
+    pub hash: Option<String>,
+    /// sha256 of the source's contents as last provisioned (which differs
+    /// from hash once local edits are merged in)
+    #[serde(default)]
+    pub source_hash: Option<String>,
+    /// root prefix the destination was provisioned under
+    #[serde(default)]
+    pub root: Option<Utf8PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EntryKind {
+    Copy,
+    Link,
+    /// the same file as the source, so it's contents change along with it
+    HardLink,
+    Directory,
+}
+
+impl Default for Manifest {
+    fn default() -> Self {
+        Self {
+            version: MANIFEST_VERSION,
+            entries: BTreeMap::new(),
+            declared: BTreeSet::new(),
+            root: None,
+        }
+    }
+}
+
+impl Manifest {
+    pub fn path() -> Result<Utf8PathBuf> {
+        Ok(crate::plugin_state_dir()?.join("manifest.json"))
+    }
+
+    pub fn load() -> Result<Self> {
+        let path = Self::path()?;
+
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                return Ok(Self::default())
+            }
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("{path}: failed reading"))
+            }
+        };
+
+        serde_json::from_reader(file)
+            .with_context(|| format!("{path}: failed parsing"))
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let path = Self::path()?;
+        if let Some(parent) = path.parent() {
+            crate::create_state_dir(parent)
+                .with_context(|| format!("{parent}: failed creating"))?;
+        }
+
+        // write to a temp file first so readers never see a partial manifest
+        let temp_path = path.with_extension("json.tmp");
+        write(&temp_path, serde_json::to_vec_pretty(self)?)
+            .with_context(|| format!("{temp_path}: failed writing"))?;
+        rename(&temp_path, &path)
+            .with_context(|| format!("{path}: failed writing"))?;
+
+        self.remove_unused_bases()
+    }
+
+    /// Directory the source's contents are kept in as they were provisioned,
+    /// to merge against once both the source and destination change
+    pub fn bases_path() -> Result<Utf8PathBuf> {
+        Ok(crate::plugin_state_dir()?.join("bases"))
+    }
+
+    /// Forget bases no entry was provisioned from anymore
+    fn remove_unused_bases(&self) -> Result<()> {
+        let path = Self::bases_path()?;
+        let bases = match path.read_dir_utf8() {
+            Ok(v) => v,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("{path}: failed listing"))
+            }
+        };
+
+        let used = self
+            .entries
+            .values()
+            .filter_map(|entry| entry.source_hash.as_deref())
+            .collect::<BTreeSet<_>>();
+
+        for base in bases {
+            let base =
+                base.with_context(|| format!("{path}: failed listing"))?;
+            if !used.contains(base.file_name()) {
+                remove_file(base.path()).with_context(|| {
+                    format!("{}: failed removing", base.path())
+                })?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn record(&mut self, destination: &Utf8Path, mut entry: ManifestEntry) {
+        // whatever was provisioned within a linked directory is now part of
+        // the link
+        if entry.kind == EntryKind::Link {
+            self.forget_beneath(destination);
+        }
+
+        entry.root.clone_from(&self.root);
+        self.entries.insert(destination.into(), entry);
+    }
+
+    pub fn forget(&mut self, destination: &Utf8Path) {
+        self.entries.remove(destination);
+    }
+
+    /// Forget everything provisioned beneath a destination (but not the
+    /// destination itself)
+    pub fn forget_beneath(&mut self, destination: &Utf8Path) {
+        self.entries.retain(|path, _| {
+            path == destination || !path.starts_with(destination)
+        });
+    }
+
+    /// Mark a destination as still wanted
+    pub fn declare(&mut self, destination: &Utf8Path) {
+        self.declared.insert(destination.into());
+    }
+
+    /// Mark a destination and everything previously provisioned beneath it
+    /// as still wanted (ie. when we couldn't tell what the state contains)
+    pub fn declare_tree(&mut self, destination: &Utf8Path) {
+        let paths = self
+            .entries
+            .keys()
+            .filter(|path| path.starts_with(destination))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        self.declared.extend(paths);
+        self.declare(destination);
+    }
+
+    pub fn is_declared(&self, destination: &Utf8Path) -> bool {
+        self.declared.contains(destination)
+    }
+
+    /// Entries which weren't declared this run, children before their parents
+    pub fn undeclared(&self) -> Vec<(Utf8PathBuf, ManifestEntry)> {
+        self.entries
+            .iter()
+            .rev()
+            .filter(|(path, entry)| {
+                entry.root == self.root && !self.declared.contains(*path)
+            })
+            .map(|(path, entry)| (path.clone(), entry.clone()))
+            .collect()
+    }
+
+    /// Declared entries of the given kind
+    pub fn declared_of_kind(&self, kind: EntryKind) -> Vec<Utf8PathBuf> {
+        self.entries
+            .iter()
+            .filter(|(path, entry)| {
+                entry.kind == kind && self.declared.contains(*path)
+            })
+            .map(|(path, _)| path.clone())
+            .collect()
+    }
+}
+
+impl ManifestEntry {
+    /// Describe the destination as it is on disk now
+    pub fn new(
+        kind: EntryKind,
+        source: Option<&Utf8Path>,
+        nk_source: Option<&Utf8Path>,
+        destination: &Utf8Path,
+    ) -> std::io::Result<Self> {
+        let metadata = destination.symlink_metadata()?;
+
+        #[cfg(unix)]
+        let mode = {
+            use std::os::unix::prelude::PermissionsExt;
+
+            (kind != EntryKind::Link)
+                .then(|| metadata.permissions().mode() & 0o777)
+        };
+        #[cfg(not(unix))]
+        let mode = {
+            let _ = metadata;
+            None
+        };
+
+        // NOTE: links can point at whole directories
+        let hash = match kind {
+            EntryKind::Copy => Some(hash_file(destination)?),
+            EntryKind::Link if destination.is_file() => {
+                Some(hash_file(destination)?)
+            }
+            EntryKind::Link | EntryKind::HardLink | EntryKind::Directory => {
+                None
+            }
+        };
+
+        Ok(Self {
+            kind,
+            source: source.map(Into::into),
+            nk_source: nk_source.map(Into::into),
+            mode,
+            hash,
+            source_hash: None,
+            root: None,
+        })
+    }
+}
+
+/// Hex encoded sha256 of a file's contents
+pub fn hash_file(path: &Utf8Path) -> std::io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher)?;
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Hex encoded sha256 of some contents
+pub fn hash_contents(contents: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(contents))
+}
+
+/// Keep the source's contents as they were provisioned
+pub fn store_base(hash: &str, contents: &str) -> Result<()> {
+    let path = Manifest::bases_path()?.join(hash);
+    if let Some(parent) = path.parent() {
+        crate::create_state_dir(parent)
+            .with_context(|| format!("{parent}: failed creating"))?;
+    }
+
+    // NOTE: only readable by the user, as rendered templates can contain
+    // secrets from nk vars
+    write_atomically(&path, Some(0o600), |file| {
+        file.write_all(contents.as_bytes())
+    })
+    .with_context(|| format!("{path}: failed writing"))
+}
+
+/// The source's contents as they were provisioned, if they were kept
+pub fn load_base(hash: &str) -> Result<Option<String>> {
+    let path = Manifest::bases_path()?.join(hash);
+
+    match std::fs::read_to_string(&path) {
+        Ok(contents) => Ok(Some(contents)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("{path}: failed reading")),
+    }
+}
@@ -0,0 +1,230 @@
+use crate::{
+    change::Change,
+    manifest::{hash_file, load_base, store_base, EntryKind, Manifest},
+    Contents, Context, NkProvisionStateResult,
+};
+use anyhow::{Context as _, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+
+/// Largest text that's kept to merge against (anything larger is treated
+/// like binary)
+const MAX_MERGE_SIZE: u64 = 1024 * 1024;
+
+/// What changed since a destination was last provisioned as a copy
+pub struct Changed {
+    pub destination: bool,
+    pub source: bool,
+    /// whether the destination differs from the source as it was last
+    /// provisioned (edited since, or with earlier edits merged in)
+    pub local_edits: bool,
+    /// hash of the source's contents as they were last provisioned
+    base: String,
+}
+
+impl Changed {
+    /// Compare against the manifest (none when it was never provisioned)
+    pub fn since_provisioned(
+        manifest: &Manifest,
+        destination: &Utf8Path,
+        source_hash: &str,
+    ) -> std::io::Result<Option<Self>> {
+        if destination.is_symlink() || !destination.is_file() {
+            return Ok(None);
+        }
+
+        let Some(entry) = manifest
+            .entries
+            .get(destination)
+            .filter(|entry| entry.kind == EntryKind::Copy)
+        else {
+            return Ok(None);
+        };
+        let Some(hash) = &entry.hash else {
+            return Ok(None);
+        };
+
+        // NOTE: entries from before the source was tracked separately were
+        // provisioned as is
+        let base = entry.source_hash.as_ref().unwrap_or(hash);
+
+        let destination = hash_file(destination)? != *hash;
+
+        Ok(Some(Self {
+            destination,
+            source: base != source_hash,
+            local_edits: destination || base != hash,
+            base: base.clone(),
+        }))
+    }
+}
+
+/// Merge local edits with the source's changes since the destination was
+/// last provisioned, reporting a conflict rather than losing either
+pub fn merge_edits(
+    context: &Context,
+    result: &mut NkProvisionStateResult,
+    source_file: &Utf8Path,
+    destination_file: &Utf8Path,
+    contents: &Contents,
+    changed: &Changed,
+) -> Result<Contents, ()> {
+    // nothing from the source to merge them with
+    if !changed.source {
+        return result.append_conflict(format!(
+            "{destination_file}: edited since it was last provisioned, set \
+             overwrite to replace it anyway"
+        ));
+    }
+
+    let texts = result.append_check(
+        merge_texts(contents, source_file, destination_file, &changed.base)
+            .map_err(|e| format!("{e:#}")),
+    )?;
+    let Some((base, ours, theirs)) = texts else {
+        return result.append_conflict(format!(
+            "{destination_file}: edited since it was last provisioned and \
+             can't be merged, set overwrite to replace it anyway"
+        ));
+    };
+
+    match diffy::merge(&base, &ours, &theirs) {
+        Ok(merged) => {
+            result
+                .output
+                .push_str("merged local edits with the source's changes\n");
+
+            Ok(Contents::Merged(merged.into_bytes()))
+        }
+        Err(conflicted) => {
+            // NOTE: written alongside, so the destination keeps working
+            // while it's resolved
+            let rejected = Utf8PathBuf::from(format!("{destination_file}.rej"));
+            result.apply_change(
+                context,
+                &Change::Write {
+                    destination: rejected.clone(),
+                    contents: conflicted.into_bytes(),
+                    // NOTE: as private as the destination it's edits are from
+                    mode: file_mode(destination_file),
+                },
+            )?;
+
+            result.append_check(Err(format!(
+                "{destination_file}: local edits conflict with the source's \
+                 changes, see {rejected}"
+            )))
+        }
+    }
+}
+
+/// Base, local and source text to merge (none if any of them isn't text, or
+/// the base wasn't kept)
+fn merge_texts(
+    contents: &Contents,
+    source_file: &Utf8Path,
+    destination_file: &Utf8Path,
+    base: &str,
+) -> Result<Option<(String, String, String)>> {
+    let Some(base) = load_base(base)? else {
+        return Ok(None);
+    };
+    let Some(ours) = read_text(destination_file)
+        .with_context(|| format!("{destination_file}: failed reading"))?
+    else {
+        return Ok(None);
+    };
+    let Some(theirs) = contents
+        .text(source_file)
+        .with_context(|| format!("{source_file}: failed reading"))?
+    else {
+        return Ok(None);
+    };
+
+    Ok(Some((base, ours, theirs)))
+}
+
+/// Keep the text the source provides, to merge against once both it and the
+/// destination change
+pub fn keep_base(
+    contents: &Contents,
+    source_file: &Utf8Path,
+    source_hash: &str,
+) -> Result<()> {
+    // NOTE: kept by hash, so unchanged sources are only ever read once
+    if Manifest::bases_path()?.join(source_hash).exists() {
+        return Ok(());
+    }
+
+    let Some(text) = contents
+        .text(source_file)
+        .with_context(|| format!("{source_file}: failed reading"))?
+    else {
+        return Ok(());
+    };
+
+    store_base(source_hash, &text)
+}
+
+/// A file's permissions, if it has any
+fn file_mode(path: &Utf8Path) -> Option<u32> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::prelude::PermissionsExt;
+
+        path.metadata()
+            .ok()
+            .map(|metadata| metadata.permissions().mode() & 0o7777)
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = path;
+        None
+    }
+}
+
+/// A file's contents, if they're text small enough to merge
+pub fn read_text(path: &Utf8Path) -> std::io::Result<Option<String>> {
+    if path.metadata()?.len() > MAX_MERGE_SIZE {
+        return Ok(None);
+    }
+
+    Ok(as_text(std::fs::read(path)?))
+}
+
+/// Contents as text, if they're text small enough to merge
+pub fn as_text(contents: Vec<u8>) -> Option<String> {
+    if contents.len() as u64 > MAX_MERGE_SIZE {
+        return None;
+    }
+
+    String::from_utf8(contents)
+        .ok()
+        .filter(|text| !text.contains('\0'))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::Sandbox;
+    use serde_json::json;
+
+    #[test]
+    fn keeps_merged_edits_when_the_source_changes_again() {
+        let sandbox = Sandbox::new("merge-again");
+        let states = json!([{
+            "declaration": "files",
+            "state": {"source": "rc", "destination": "~/rc"},
+        }]);
+
+        sandbox.write(&sandbox.source("rc"), "a\n\nb\n\nc\n\nd\n");
+        assert!(!sandbox.provision(states.clone(), false));
+
+        sandbox.write(&sandbox.home("rc"), "a\n\nB\n\nc\n\nd\n");
+        sandbox.write(&sandbox.source("rc"), "A\n\nb\n\nc\n\nd\n");
+        assert!(!sandbox.provision(states.clone(), false));
+        assert_eq!(sandbox.read(&sandbox.home("rc")), "A\n\nB\n\nc\n\nd\n");
+
+        sandbox.write(&sandbox.source("rc"), "A\n\nb\n\nc\n\nD\n");
+        assert!(!sandbox.provision(states, false));
+        assert_eq!(sandbox.read(&sandbox.home("rc")), "A\n\nB\n\nc\n\nD\n");
+    }
+}
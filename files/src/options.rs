@@ -0,0 +1,253 @@
+use camino::{Utf8Path, Utf8PathBuf};
+use faccess::PathExt;
+use serde::{de::Error, Deserialize, Deserializer, Serializer};
+use std::fmt::Display;
+
+/// Options which can be set on each state, or as defaults for later states
+/// with `files.settings`
+#[derive(Debug, Default, Clone, Deserialize)]
+pub struct Options {
+    /// permissions masked out of the default modes and of created parents
+    /// (defaults to 0077)
+    #[serde(default, deserialize_with = "deserialize_mode")]
+    pub umask: Option<u32>,
+    /// permissions of provisioned files (defaults to 0666, or 0777 for
+    /// executable sources, less the umask)
+    #[serde(default, deserialize_with = "deserialize_mode")]
+    pub mode: Option<u32>,
+    /// permissions of provisioned directories (defaults to 0777, less the
+    /// umask)
+    #[serde(default, deserialize_with = "deserialize_mode")]
+    pub dir_mode: Option<u32>,
+    /// take permissions from the source, unless mode/dir_mode are set
+    pub preserve_source_mode: Option<bool>,
+    /// user to provision for, `~` is their home and provisioned paths are
+    /// owned by them
+    pub user: Option<Owner>,
+    /// user provisioned paths are owned by (defaults to the target user)
+    pub owner: Option<Owner>,
+    /// group provisioned paths are owned by (defaults to the target user's
+    /// primary group)
+    pub group: Option<Owner>,
+    /// which parents of destinations get their permissions fixed up
+    /// (defaults to only those created)
+    pub parent_policy: Option<ParentPolicy>,
+    /// link relative to the destination's directory, rather than to the
+    /// source's absolute path
+    pub relative_links: Option<bool>,
+    /// back up anything overwritten or deleted (defaults to true)
+    pub backup: Option<bool>,
+    /// replace files and non-empty directories which weren't provisioned
+    /// (defaults to false)
+    pub force: Option<bool>,
+    /// replace files which were edited since they were last provisioned
+    /// (defaults to false)
+    pub overwrite: Option<bool>,
+}
+
+/// A user or group, by name or id
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum Owner {
+    Id(u32),
+    Name(String),
+}
+
+impl Display for Owner {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Id(id) => write!(f, "{id}"),
+            Self::Name(name) => write!(f, "{name}"),
+        }
+    }
+}
+
+/// Which parent directories of a destination get `dir_mode`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ParentPolicy {
+    /// never change parents' permissions (those created get 0777, less the
+    /// umask)
+    None,
+    /// only change the permissions of parents created while provisioning
+    Created,
+    /// also change existing parents' permissions, up to (but not including)
+    /// this directory
+    Root(Utf8PathBuf),
+}
+
+impl Options {
+    /// These options, falling back to the given defaults for anything unset
+    pub fn or(&self, defaults: &Self) -> Self {
+        Self {
+            umask: self.umask.or(defaults.umask),
+            mode: self.mode.or(defaults.mode),
+            dir_mode: self.dir_mode.or(defaults.dir_mode),
+            preserve_source_mode: self
+                .preserve_source_mode
+                .or(defaults.preserve_source_mode),
+            user: self.user.clone().or_else(|| defaults.user.clone()),
+            owner: self.owner.clone().or_else(|| defaults.owner.clone()),
+            group: self.group.clone().or_else(|| defaults.group.clone()),
+            parent_policy: self
+                .parent_policy
+                .clone()
+                .or_else(|| defaults.parent_policy.clone()),
+            relative_links: self.relative_links.or(defaults.relative_links),
+            backup: self.backup.or(defaults.backup),
+            force: self.force.or(defaults.force),
+            overwrite: self.overwrite.or(defaults.overwrite),
+        }
+    }
+
+    pub fn relative_links(&self) -> bool {
+        self.relative_links.unwrap_or(false)
+    }
+
+    pub fn parent_policy(&self) -> ParentPolicy {
+        self.parent_policy.clone().unwrap_or(ParentPolicy::Created)
+    }
+
+    pub fn umask(&self) -> u32 {
+        self.umask.unwrap_or(0o077)
+    }
+
+    pub fn backup(&self) -> bool {
+        self.backup.unwrap_or(true)
+    }
+
+    pub fn force(&self) -> bool {
+        self.force.unwrap_or(false)
+    }
+
+    pub fn overwrite(&self) -> bool {
+        self.overwrite.unwrap_or(false)
+    }
+
+    /// Permissions for a provisioned file (none where permissions aren't
+    /// supported)
+    pub fn file_mode(&self, source_file: &Utf8Path) -> Option<u32> {
+        if !cfg!(unix) {
+            return None;
+        }
+
+        self.mode
+            .or_else(|| self.source_mode(source_file))
+            .or_else(|| {
+                let mode = if source_file.as_std_path().executable() {
+                    0o777
+                } else {
+                    0o666
+                };
+
+                Some(mode & !self.umask())
+            })
+    }
+
+    /// Permissions for a provisioned directory (none where permissions
+    /// aren't supported)
+    pub fn dir_mode(&self, source_dir: Option<&Utf8Path>) -> Option<u32> {
+        if !cfg!(unix) {
+            return None;
+        }
+
+        self.dir_mode
+            .or_else(|| source_dir.and_then(|dir| self.source_mode(dir)))
+            .or_else(|| Some(0o777 & !self.umask()))
+    }
+
+    /// Permissions parents are created with (none where permissions aren't
+    /// supported)
+    pub fn parent_mode(&self) -> Option<u32> {
+        cfg!(unix).then(|| 0o777 & !self.umask())
+    }
+
+    fn source_mode(&self, source: &Utf8Path) -> Option<u32> {
+        if !self.preserve_source_mode.unwrap_or(false) {
+            return None;
+        }
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::prelude::PermissionsExt;
+
+            source
+                .metadata()
+                .ok()
+                .map(|metadata| metadata.permissions().mode() & 0o7777)
+        }
+
+        #[cfg(not(unix))]
+        {
+            let _ = source;
+            None
+        }
+    }
+}
+
+/// Accepts either a number or an octal string (ie. "0644" or "0o644"), as
+/// numbers are easy to accidentally write in decimal
+pub fn deserialize_mode<'de, D>(
+    deserializer: D,
+) -> Result<Option<u32>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Mode {
+        Number(u32),
+        String(String),
+    }
+
+    let mode = match Option::<Mode>::deserialize(deserializer)? {
+        None => return Ok(None),
+        Some(Mode::Number(mode)) => mode,
+        Some(Mode::String(mode)) => {
+            let digits = mode.strip_prefix("0o").unwrap_or(&mode);
+            u32::from_str_radix(digits, 8).map_err(|e| {
+                D::Error::custom(format!("{e}: invalid mode: {mode}"))
+            })?
+        }
+    };
+
+    if mode > 0o7777 {
+        return Err(D::Error::custom(format!("invalid mode: {mode:o}")));
+    }
+
+    Ok(Some(mode))
+}
+
+pub fn deserialize_required_mode<'de, D>(
+    deserializer: D,
+) -> Result<u32, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    deserialize_mode(deserializer)?
+        .ok_or_else(|| D::Error::custom("missing mode"))
+}
+
+/// Writes modes as octal strings (ie. "0644"), just as they're configured
+pub fn serialize_mode<S>(
+    mode: &Option<u32>,
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    match mode {
+        Some(mode) => serialize_required_mode(mode, serializer),
+        None => serializer.serialize_none(),
+    }
+}
+
+pub fn serialize_required_mode<S>(
+    mode: &u32,
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serializer.serialize_str(&format!("{mode:04o}"))
+}
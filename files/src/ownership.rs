@@ -0,0 +1,169 @@
+use crate::options::{Options, Owner};
+use anyhow::Result;
+use camino::{Utf8Path, Utf8PathBuf};
+
+/// Who provisioned paths should be owned by, resolved to ids
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Ownership {
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+}
+
+impl Ownership {
+    /// Look up the owner and group options, falling back to the target user
+    /// (ownership is left alone where it isn't supported)
+    ///
+    /// NOTE: names are looked up in the root's databases when provisioning
+    /// under a root prefix
+    pub fn resolve(options: &Options, root: Option<&Utf8Path>) -> Result<Self> {
+        #[cfg(unix)]
+        {
+            let user = User::resolve(options, root)?;
+
+            Ok(Self {
+                uid: match &options.owner {
+                    Some(Owner::Id(uid)) => Some(*uid),
+                    Some(owner) => Some(lookup_user(owner, root)?.uid),
+                    None => user.as_ref().map(|user| user.uid),
+                },
+                gid: match &options.group {
+                    Some(Owner::Id(gid)) => Some(*gid),
+                    Some(group) => Some(lookup_gid(group, root)?),
+                    None => user.as_ref().map(|user| user.gid),
+                },
+            })
+        }
+
+        #[cfg(not(unix))]
+        {
+            let _ = (options, root);
+            Ok(Self::default())
+        }
+    }
+
+    pub const fn is_empty(&self) -> bool {
+        self.uid.is_none() && self.gid.is_none()
+    }
+
+    /// Whether a path with the given owner and group needs changing
+    pub fn differs(&self, uid: u32, gid: u32) -> bool {
+        self.uid.is_some_and(|v| v != uid) || self.gid.is_some_and(|v| v != gid)
+    }
+}
+
+/// The user a state provisions for, from the passwd database
+#[derive(Debug, Clone)]
+pub struct User {
+    pub name: String,
+    pub uid: u32,
+    pub gid: u32,
+    /// NOTE: never includes the root prefix
+    pub home: Utf8PathBuf,
+}
+
+impl User {
+    /// Look up the target user option (none when provisioning for whoever
+    /// runs the plugin)
+    pub fn resolve(
+        options: &Options,
+        root: Option<&Utf8Path>,
+    ) -> Result<Option<Self>> {
+        let Some(user) = &options.user else {
+            return Ok(None);
+        };
+
+        #[cfg(unix)]
+        {
+            lookup_user(user, root).map(Some)
+        }
+
+        #[cfg(not(unix))]
+        {
+            let _ = root;
+            Err(anyhow::anyhow!(
+                "{user}: provisioning for other users isn't supported on \
+                 this platform"
+            ))
+        }
+    }
+}
+
+#[cfg(unix)]
+fn lookup_user(user: &Owner, root: Option<&Utf8Path>) -> Result<User> {
+    use anyhow::anyhow;
+    use uzers::os::unix::UserExt;
+
+    if let Some(root) = root {
+        let fields = find_in_database(&root.join("etc/passwd"), user)?
+            .ok_or_else(|| anyhow!("{user}: no such user in {root}"))?;
+
+        return Ok(User {
+            name: fields.first().cloned().unwrap_or_default(),
+            uid: parse_field(&fields, 2)?,
+            gid: parse_field(&fields, 3)?,
+            home: fields.get(5).cloned().unwrap_or_default().into(),
+        });
+    }
+
+    let found = match user {
+        Owner::Id(uid) => uzers::get_user_by_uid(*uid),
+        Owner::Name(name) => uzers::get_user_by_name(name),
+    }
+    .ok_or_else(|| anyhow!("{user}: no such user"))?;
+
+    Ok(User {
+        name: found.name().to_string_lossy().into(),
+        uid: found.uid(),
+        gid: found.primary_group_id(),
+        home: Utf8PathBuf::try_from(found.home_dir().to_path_buf())?,
+    })
+}
+
+#[cfg(unix)]
+fn lookup_gid(group: &Owner, root: Option<&Utf8Path>) -> Result<u32> {
+    use anyhow::anyhow;
+
+    if let Some(root) = root {
+        let fields = find_in_database(&root.join("etc/group"), group)?
+            .ok_or_else(|| anyhow!("{group}: no such group in {root}"))?;
+
+        return parse_field(&fields, 2);
+    }
+
+    match group {
+        Owner::Id(gid) => uzers::get_group_by_gid(*gid),
+        Owner::Name(name) => uzers::get_group_by_name(name),
+    }
+    .map(|group| group.gid())
+    .ok_or_else(|| anyhow!("{group}: no such group"))
+}
+
+/// Find a user or group in a passwd style database (ie. where each line is
+/// `name:password:id:...`)
+#[cfg(unix)]
+fn find_in_database(
+    path: &Utf8Path,
+    owner: &Owner,
+) -> Result<Option<Vec<String>>> {
+    use anyhow::Context;
+
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("{path}: failed reading"))?;
+
+    Ok(contents
+        .lines()
+        .map(|line| line.split(':').map(String::from).collect::<Vec<_>>())
+        .find(|fields| match owner {
+            Owner::Id(id) => fields.get(2) == Some(&id.to_string()),
+            Owner::Name(name) => fields.first() == Some(name),
+        }))
+}
+
+#[cfg(unix)]
+fn parse_field(fields: &[String], index: usize) -> Result<u32> {
+    let field = fields.get(index).map(String::as_str).unwrap_or_default();
+
+    field
+        .parse()
+        .map_err(|e| anyhow::anyhow!("{e}: invalid id: {}", fields.join(":")))
+}
@@ -0,0 +1,444 @@
+use crate::{
+    args::{self, Apply, Provision},
+    change::{write_atomically, Change},
+    manifest::{hash_file, EntryKind, Manifest},
+    options::{deserialize_mode, serialize_mode},
+    print_result, provision, Context, NkProvisionStateResult,
+    NkProvisionStateStatus,
+};
+use anyhow::{anyhow, Context as _, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeSet,
+    fmt::Display,
+    fs::File,
+    io::{ErrorKind, Write},
+};
+
+const PLAN_VERSION: u32 = 1;
+
+/// Every change provisioning would make, along with what each relies on
+/// staying as it was when planned
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Plan {
+    pub version: u32,
+    /// root prefix the plan was made under
+    pub root: Option<Utf8PathBuf>,
+    pub steps: Vec<Step>,
+    pub provisioned: Vec<Provisioned>,
+    /// paths earlier steps change, which later steps rely on instead of what
+    /// was there when planned
+    #[serde(skip)]
+    changed: BTreeSet<Utf8PathBuf>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Step {
+    /// result the change is part of, it's steps are applied together
+    pub description: String,
+    pub change: Change,
+    pub preconditions: Vec<Precondition>,
+}
+
+/// What's recorded in the manifest once a result's steps are applied
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Provisioned {
+    pub description: String,
+    pub destination: Utf8PathBuf,
+    pub kind: EntryKind,
+    pub source: Option<Utf8PathBuf>,
+    pub nk_source: Option<Utf8PathBuf>,
+    pub source_hash: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Precondition {
+    pub path: Utf8PathBuf,
+    pub state: PathState,
+}
+
+/// What's at a path, as far as a change relies on it
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", tag = "kind")]
+pub enum PathState {
+    Missing,
+    File {
+        hash: String,
+        #[serde(serialize_with = "serialize_mode")]
+        #[serde(deserialize_with = "deserialize_mode")]
+        mode: Option<u32>,
+    },
+    Directory {
+        #[serde(serialize_with = "serialize_mode")]
+        #[serde(deserialize_with = "deserialize_mode")]
+        mode: Option<u32>,
+    },
+    Symlink {
+        target: Utf8PathBuf,
+    },
+}
+
+impl Plan {
+    pub const fn new(root: Option<Utf8PathBuf>) -> Self {
+        Self {
+            version: PLAN_VERSION,
+            root,
+            steps: vec![],
+            provisioned: vec![],
+            changed: BTreeSet::new(),
+        }
+    }
+
+    pub fn load(path: &Utf8Path) -> Result<Self> {
+        let file = File::open(path)
+            .with_context(|| format!("{path}: failed reading"))?;
+        let plan: Self = serde_json::from_reader(file)
+            .with_context(|| format!("{path}: failed parsing"))?;
+
+        // NOTE: an audited plan is applied exactly, or not at all
+        if plan.version != PLAN_VERSION {
+            return Err(anyhow!(
+                "{path}: unsupported plan version {}",
+                plan.version
+            ));
+        }
+
+        Ok(plan)
+    }
+
+    pub fn save(&self, path: &Utf8Path) -> Result<()> {
+        let plan = serde_json::to_vec_pretty(self)?;
+
+        // NOTE: only readable by the user, as rendered templates can contain
+        // secrets from nk vars
+        write_atomically(path, Some(0o600), |file| file.write_all(&plan))
+            .with_context(|| format!("{path}: failed writing"))
+    }
+
+    /// Plan a change, along with the state of the paths it relies on
+    pub fn record(
+        &mut self,
+        description: &str,
+        change: &Change,
+    ) -> std::io::Result<()> {
+        let mut preconditions = vec![];
+
+        // NOTE: anything at (or beneath) a path an earlier step changes
+        // relies on that step, rather than what's there now
+        let path = change.path();
+        if !self.changed.iter().any(|changed| path.starts_with(changed)) {
+            preconditions.push(Precondition {
+                path: path.into(),
+                state: PathState::of(path)?,
+            });
+        }
+        self.changed.insert(path.into());
+
+        if let Some(source) = change.source() {
+            preconditions.push(Precondition {
+                path: source.into(),
+                state: PathState::of(source)?,
+            });
+        }
+
+        self.steps.push(Step {
+            description: description.into(),
+            change: change.clone(),
+            preconditions,
+        });
+
+        Ok(())
+    }
+
+    /// Plan to record a destination in the manifest, along with the source
+    /// file and nk source it's provisioned from (if any)
+    pub fn record_provisioned(
+        &mut self,
+        description: &str,
+        kind: EntryKind,
+        source: Option<(&Utf8Path, &Utf8Path)>,
+        destination: &Utf8Path,
+        source_hash: Option<String>,
+    ) {
+        let (source, nk_source) = source.unzip();
+
+        self.provisioned.push(Provisioned {
+            description: description.into(),
+            destination: destination.into(),
+            kind,
+            source: source.map(Into::into),
+            nk_source: nk_source.map(Into::into),
+            source_hash,
+        });
+    }
+}
+
+impl PathState {
+    /// What's at a path now (never following links)
+    pub fn of(path: &Utf8Path) -> std::io::Result<Self> {
+        let metadata = match path.symlink_metadata() {
+            Ok(v) => v,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                return Ok(Self::Missing)
+            }
+            Err(e) => return Err(e),
+        };
+
+        if metadata.is_symlink() {
+            return Ok(Self::Symlink {
+                target: path.read_link_utf8()?,
+            });
+        }
+
+        #[cfg(unix)]
+        let mode = {
+            use std::os::unix::prelude::PermissionsExt;
+
+            Some(metadata.permissions().mode() & 0o7777)
+        };
+        #[cfg(not(unix))]
+        let mode = None;
+
+        if metadata.is_dir() {
+            Ok(Self::Directory { mode })
+        } else {
+            Ok(Self::File {
+                hash: hash_file(path)?,
+                mode,
+            })
+        }
+    }
+}
+
+impl Display for PathState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let with_mode = |mode: &Option<u32>| {
+            mode.map(|mode| format!(" with mode {mode:o}"))
+                .unwrap_or_default()
+        };
+
+        match self {
+            Self::Missing => write!(f, "missing"),
+            Self::File { hash, mode } => {
+                write!(f, "file {hash}{}", with_mode(mode))
+            }
+            Self::Directory { mode } => {
+                write!(f, "directory{}", with_mode(mode))
+            }
+            Self::Symlink { target } => write!(f, "link to {target}"),
+        }
+    }
+}
+
+/// Plan what provisioning would change, to be reviewed then applied
+pub fn plan(args: args::Plan) {
+    provision(
+        Provision {
+            info: args.info,
+            dry_run: true,
+            max_diff_lines: args.max_diff_lines,
+            prune: false,
+            backup_retention_days: 0,
+            root: args.root,
+        },
+        false,
+        Some(args.plan),
+    );
+}
+
+/// Make exactly the changes in a plan, refusing any whose preconditions no
+/// longer hold
+pub fn apply(args: Apply) {
+    let plan = match Plan::load(&args.plan) {
+        Ok(v) => v,
+        Err(e) => {
+            print_failure(&format!("{e:#}"));
+            return;
+        }
+    };
+
+    let mut manifest = match Manifest::load() {
+        Ok(v) => v,
+        Err(e) => {
+            print_failure(&format!("{e:#}"));
+            return;
+        }
+    };
+    manifest.root.clone_from(&plan.root);
+
+    // NOTE: the plan records full paths, root prefix and all
+    let context = match Context::new(args.dry_run) {
+        Ok(v) => v,
+        Err(e) => {
+            print_failure(&format!("{e:#}"));
+            return;
+        }
+    };
+
+    // NOTE: a plan is applied exactly or not at all, so everything it relies
+    // on is checked before anything's changed
+    let mut refused = false;
+    for steps in plan
+        .steps
+        .chunk_by(|step, next| step.description == next.description)
+    {
+        let mut result = step_result(&steps[0]);
+
+        let _ = steps
+            .iter()
+            .try_for_each(|step| check_preconditions(&mut result, step));
+
+        if matches!(result.status, NkProvisionStateStatus::Failed) {
+            print_result(&result);
+            refused = true;
+        }
+    }
+    if refused {
+        print_failure("changed since it was planned, nothing was applied");
+        return;
+    }
+
+    // NOTE: each result's steps rely on the ones before, so they stop at the
+    // first that can't be applied
+    for steps in plan
+        .steps
+        .chunk_by(|step, next| step.description == next.description)
+    {
+        let mut result = step_result(&steps[0]);
+
+        // NOTE: result is exclusively used to make it's implementation
+        // cleaner (so we can exit if any change fails), all success/failure
+        // details are returned through the mutable result
+        let _ = steps
+            .iter()
+            .try_for_each(|step| apply_step(&context, &mut result, step))
+            .and_then(|()| {
+                // NOTE: recorded just as provisioning would have
+                plan.provisioned
+                    .iter()
+                    .filter(|provisioned| {
+                        provisioned.description == steps[0].description
+                    })
+                    .try_for_each(|provisioned| {
+                        result.record_in_manifest(
+                            &context,
+                            &mut manifest,
+                            provisioned.kind,
+                            provisioned
+                                .source
+                                .as_deref()
+                                .zip(provisioned.nk_source.as_deref()),
+                            &provisioned.destination,
+                            provisioned.source_hash.clone(),
+                        )
+                    })
+            });
+
+        print_result(&result);
+    }
+
+    if !context.dry_run {
+        if let Err(e) = manifest.save() {
+            print_failure(&format!("{e:#}"));
+        }
+    }
+}
+
+fn step_result(step: &Step) -> NkProvisionStateResult {
+    NkProvisionStateResult {
+        status: NkProvisionStateStatus::Success,
+        changed: false,
+        description: step.description.clone(),
+        output: String::new(),
+    }
+}
+
+/// Refuse a step if anything it relies on changed since it was planned
+fn check_preconditions(
+    result: &mut NkProvisionStateResult,
+    step: &Step,
+) -> Result<(), ()> {
+    for precondition in &step.preconditions {
+        let path = &precondition.path;
+        let state = result.append_check(
+            PathState::of(path)
+                .map_err(|e| format!("{e}: failed checking: {path}")),
+        )?;
+
+        if state != precondition.state {
+            return result.append_check(Err(format!(
+                "{path}: changed since it was planned, refusing to {}\n\
+                 planned: {}\nnow: {state}",
+                step.change, precondition.state
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+fn apply_step(
+    context: &Context,
+    result: &mut NkProvisionStateResult,
+    step: &Step,
+) -> Result<(), ()> {
+    // NOTE: backed up into this run, rather than the one it was planned in
+    let change = match &step.change {
+        Change::Backup { path, .. } => Change::Backup {
+            path: path.clone(),
+            backup: context.backups.path_for(path),
+        },
+        change => change.clone(),
+    };
+
+    result.apply_change(context, &change)
+}
+
+fn print_failure(output: &str) {
+    crate::print_failure("files apply", output);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::apply;
+    use crate::{args::Apply, testing::Sandbox, ANY_FAILED};
+    use serde_json::json;
+    use std::sync::atomic::Ordering;
+
+    #[test]
+    fn applies_nothing_once_anything_changed() {
+        let sandbox = Sandbox::new("apply-changed");
+        let other = json!({
+            "declaration": "files",
+            "state": {"source": "other", "destination": "~/other"},
+        });
+        let rc = json!({
+            "declaration": "files",
+            "state": {"source": "rc", "destination": "~/rc"},
+        });
+        let plan = sandbox.dir.join("plan.json");
+
+        sandbox.write(&sandbox.source("rc"), "v1");
+        assert!(!sandbox.provision(json!([rc]), false));
+
+        sandbox.write(&sandbox.source("other"), "other");
+        sandbox.write(&sandbox.source("rc"), "v2");
+        assert!(!sandbox.provision_with(
+            json!([other, rc]),
+            false,
+            true,
+            Some(plan.clone())
+        ));
+
+        sandbox.write(&sandbox.source("rc"), "v3");
+        apply(Apply {
+            plan,
+            dry_run: false,
+        });
+
+        assert!(ANY_FAILED.load(Ordering::Relaxed));
+        assert_eq!(sandbox.read(&sandbox.home("rc")), "v1");
+        assert!(!sandbox.home("other").exists());
+    }
+}
@@ -0,0 +1,256 @@
+use crate::{
+    change::Change,
+    is_hardlinked_to, is_link_into, link_target,
+    manifest::{hash_file, EntryKind, Manifest, ManifestEntry},
+    print_result, Context, NkProvisionStateResult, NkProvisionStateStatus,
+};
+use camino::{Utf8Path, Utf8PathBuf};
+use std::collections::BTreeSet;
+
+/// Delete destinations which were provisioned previously but are no longer
+/// declared by any state
+pub fn prune(context: &Context, manifest: &mut Manifest) {
+    // NOTE: tracked so dry runs can tell which directories would be left empty
+    let mut removed = BTreeSet::new();
+
+    for (destination, entry) in manifest.undeclared() {
+        let mut result = prune_result(context, &destination);
+
+        // NOTE: result is exclusively used to make it's implementation
+        // cleaner (so we can exit if any change fails), all success/failure
+        // details are returned through the mutable result
+        if prune_entry(context, &mut result, &removed, &destination, &entry)
+            .is_ok()
+        {
+            if result.changed {
+                removed.insert(destination.clone());
+            }
+            if !context.dry_run {
+                manifest.forget(&destination);
+            }
+        }
+
+        print_result(&result);
+    }
+
+    // dangling links left behind in declared directories (ie. when a linked
+    // file is deleted from the nk source before the manifest knew about it)
+    for directory in manifest.declared_of_kind(EntryKind::Directory) {
+        let Ok(children) = directory.read_dir_utf8() else {
+            continue;
+        };
+
+        for child in children.flatten() {
+            let link = child.path();
+            if manifest.is_declared(link)
+                || removed.contains(link)
+                || !is_dangling_link_into(link, &context.nk_sources)
+            {
+                continue;
+            }
+
+            let mut result = prune_result(context, link);
+            let _ = result
+                .apply_change(context, &Change::DeleteSymlink(link.into()));
+
+            print_result(&result);
+        }
+    }
+}
+
+fn prune_result(
+    context: &Context,
+    destination: &Utf8Path,
+) -> NkProvisionStateResult {
+    NkProvisionStateResult {
+        status: NkProvisionStateStatus::Success,
+        changed: false,
+        description: format!("prune {}", context.display_path(destination)),
+        output: String::new(),
+    }
+}
+
+fn prune_entry(
+    context: &Context,
+    result: &mut NkProvisionStateResult,
+    removed: &BTreeSet<Utf8PathBuf>,
+    destination: &Utf8Path,
+    entry: &ManifestEntry,
+) -> Result<(), ()> {
+    // already gone
+    if !destination.is_symlink() && !destination.exists() {
+        return Ok(());
+    }
+
+    match entry.kind {
+        EntryKind::Link => {
+            if !destination.is_symlink() {
+                leave_in_place(result, "no longer a link");
+                return Ok(());
+            }
+
+            let target =
+                result.append_check(link_target(destination).map_err(|e| {
+                    format!("{e}: failed reading link: {destination}")
+                }))?;
+
+            // only remove links that still point where we linked them
+            if entry.source.as_ref() != Some(&target)
+                && !context.nk_sources.iter().any(|s| target.starts_with(s))
+            {
+                leave_in_place(result, "relinked since provisioned");
+                return Ok(());
+            }
+
+            result.remove_existing(context, destination, true)
+        }
+        EntryKind::Copy => {
+            if destination.is_symlink() || !destination.is_file() {
+                leave_in_place(result, "no longer a file");
+                return Ok(());
+            }
+
+            // only remove files that haven't been edited since
+            let hash =
+                result.append_check(hash_file(destination).map_err(|e| {
+                    format!("{e}: failed hashing file: {destination}")
+                }))?;
+            if entry.hash.as_ref() != Some(&hash) {
+                leave_in_place(result, "modified since provisioned");
+                return Ok(());
+            }
+
+            result.remove_existing(context, destination, true)
+        }
+        EntryKind::HardLink => {
+            if destination.is_symlink() || !destination.is_file() {
+                leave_in_place(result, "no longer a file");
+                return Ok(());
+            }
+
+            // NOTE: contents change along with the source, so only remove
+            // hard links that are still the same file
+            let is_hardlinked_to = match &entry.source {
+                Some(source) if source.exists() => result.append_check(
+                    is_hardlinked_to(destination, source).map_err(|e| {
+                        format!("{e}: failed checking hard link: {destination}")
+                    }),
+                )?,
+                // nothing left to compare it to
+                _ => false,
+            };
+            if !is_hardlinked_to {
+                leave_in_place(result, "no longer hard linked to it's source");
+                return Ok(());
+            }
+
+            result.remove_existing(context, destination, true)
+        }
+        EntryKind::Directory => {
+            if destination.is_symlink() || !destination.is_dir() {
+                leave_in_place(result, "no longer a directory");
+                return Ok(());
+            }
+
+            result.remove_if_left_empty(context, destination, removed)
+        }
+    }
+}
+
+fn leave_in_place(result: &mut NkProvisionStateResult, reason: &str) {
+    result
+        .output
+        .push_str(&format!("{reason}, left in place\n"));
+}
+
+fn is_dangling_link_into(path: &Utf8Path, nk_sources: &[Utf8PathBuf]) -> bool {
+    path.is_symlink() && !path.exists() && is_link_into(path, nk_sources)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::Sandbox;
+    use serde_json::json;
+
+    #[test]
+    fn removes_undeclared_destinations() {
+        let sandbox = Sandbox::new("prune-undeclared");
+        sandbox.write(&sandbox.source("kept"), "kept");
+        sandbox.write(&sandbox.source("dir/removed"), "removed");
+
+        let kept = json!({
+            "declaration": "files",
+            "state": {"source": "kept", "destination": "~/kept"},
+        });
+        let removed = json!({
+            "declaration": "files",
+            "state": {"source": "dir", "destination": "~/dir"},
+        });
+        assert!(!sandbox.provision(json!([kept, removed]), false));
+        assert!(!sandbox.provision(json!([kept]), true));
+
+        assert!(sandbox.home("kept").exists());
+        assert!(!sandbox.home("dir/removed").exists());
+        assert!(!sandbox.home("dir").exists());
+    }
+
+    #[test]
+    fn keeps_destinations_of_failed_states() {
+        let sandbox = Sandbox::new("prune-failed");
+        sandbox.write(&sandbox.source("dir/a"), "a");
+        sandbox.write(&sandbox.source("dir/b"), "b");
+
+        assert!(!sandbox.provision(
+            json!([{
+                "declaration": "files",
+                "state": {"source": "dir", "destination": "~/dir"},
+            }]),
+            false,
+        ));
+
+        // fails before anything within it is provisioned
+        assert!(sandbox.provision(
+            json!([{
+                "declaration": "files",
+                "state": {
+                    "source": "dir",
+                    "destination": "~/dir",
+                    "link_files": true,
+                    "template": true,
+                },
+            }]),
+            true,
+        ));
+
+        assert_eq!(sandbox.read(&sandbox.home("dir/a")), "a");
+        assert_eq!(sandbox.read(&sandbox.home("dir/b")), "b");
+    }
+
+    #[test]
+    fn skips_pruning_when_a_state_cant_be_resolved() {
+        let sandbox = Sandbox::new("prune-unresolved");
+        sandbox.write(&sandbox.source("dir/a"), "a");
+
+        assert!(!sandbox.provision(
+            json!([{
+                "declaration": "files",
+                "state": {"source": "dir", "destination": "~/dir"},
+            }]),
+            false,
+        ));
+
+        assert!(sandbox.provision(
+            json!([{
+                "declaration": "files",
+                "state": {
+                    "source": "dir",
+                    "destination": "~/dir",
+                    "user": "nk-files-no-such-user",
+                },
+            }]),
+            true,
+        ));
+
+        assert_eq!(sandbox.read(&sandbox.home("dir/a")), "a");
+    }
+}
@@ -0,0 +1,183 @@
+use crate::{
+    args::Restore,
+    backup::{Backups, JournalEntry},
+    change::Change,
+    manifest::Manifest,
+    print_result, Context, NkProvisionStateResult, NkProvisionStateStatus,
+};
+use camino::Utf8PathBuf;
+use std::collections::BTreeSet;
+
+/// Undo a run, putting back what it backed up and removing what it created
+pub fn restore(args: Restore) {
+    let runs = match Backups::runs() {
+        Ok(v) => v,
+        Err(e) => {
+            print_failure(&format!("{e:#}"));
+            return;
+        }
+    };
+
+    if args.list {
+        list_runs(&runs);
+        return;
+    }
+
+    let Some(run) = args.run.or_else(|| runs.last().cloned()) else {
+        print_failure("no backup runs to restore");
+        return;
+    };
+
+    let journal = match Backups::journal(&run) {
+        Ok(v) => v,
+        Err(e) => {
+            print_failure(&format!("{e:#}"));
+            return;
+        }
+    };
+
+    let mut manifest = match Manifest::load() {
+        Ok(v) => v,
+        Err(e) => {
+            print_failure(&format!("{e:#}"));
+            return;
+        }
+    };
+
+    // NOTE: restoring is itself backed up, so it can be undone too
+    let context = match Context::new(args.dry_run) {
+        Ok(v) => v,
+        Err(e) => {
+            print_failure(&format!("{e:#}"));
+            return;
+        }
+    };
+
+    let destination = args.destination.map(|destination| {
+        Utf8PathBuf::from(shellexpand::tilde(destination.as_str()).as_ref())
+    });
+
+    // NOTE: tracked so dry runs can tell which directories would be left empty
+    let mut removed = BTreeSet::new();
+
+    // undo in the reverse order it was done
+    for entry in journal.iter().rev() {
+        let path = entry.path();
+        if destination
+            .as_ref()
+            .is_some_and(|destination| !path.starts_with(destination))
+        {
+            continue;
+        }
+
+        let action = match entry {
+            JournalEntry::BackedUp { .. } => "restore",
+            JournalEntry::Created { .. } => "remove",
+        };
+        let mut result = NkProvisionStateResult {
+            status: NkProvisionStateStatus::Success,
+            changed: false,
+            description: format!("{action} {}", context.display_path(path)),
+            output: String::new(),
+        };
+
+        // NOTE: result is exclusively used to make it's implementation
+        // cleaner (so we can exit if any change fails), all success/failure
+        // details are returned through the mutable result
+        if restore_entry(&context, &mut result, &removed, entry).is_ok() {
+            if result.changed {
+                removed.insert(path.to_path_buf());
+            }
+
+            // no longer what was provisioned
+            manifest.forget(path);
+        }
+
+        print_result(&result);
+    }
+
+    if !context.dry_run {
+        if let Err(e) = manifest.save() {
+            print_failure(&format!("{e:#}"));
+        }
+    }
+}
+
+fn restore_entry(
+    context: &Context,
+    result: &mut NkProvisionStateResult,
+    removed: &BTreeSet<Utf8PathBuf>,
+    entry: &JournalEntry,
+) -> Result<(), ()> {
+    match entry {
+        JournalEntry::Created { path } => {
+            // already gone
+            if !path.is_symlink() && !path.exists() {
+                return Ok(());
+            }
+
+            if path.is_symlink() || !path.is_dir() {
+                return result.remove_existing(context, path, true);
+            }
+
+            result.remove_if_left_empty(context, path, removed)
+        }
+        JournalEntry::BackedUp { path, backup } => {
+            if !backup.is_symlink() && !backup.exists() {
+                return result.append_check(Err(format!(
+                    "backup no longer exists: {backup}"
+                )));
+            }
+
+            // get whatever replaced it out of the way
+            if (path.is_symlink() || path.exists()) && !removed.contains(path) {
+                result.remove_existing(context, path, true)?;
+            }
+
+            result.apply_change(
+                context,
+                &Change::Restore {
+                    backup: backup.clone(),
+                    path: path.clone(),
+                },
+            )
+        }
+    }
+}
+
+fn list_runs(runs: &[String]) {
+    for run in runs {
+        let mut result = NkProvisionStateResult {
+            status: NkProvisionStateStatus::Success,
+            changed: false,
+            description: format!("backup run {run}"),
+            output: String::new(),
+        };
+
+        match Backups::journal(run) {
+            Ok(journal) => {
+                for entry in journal {
+                    result.output.push_str(&describe_entry(&entry));
+                }
+            }
+            Err(e) => {
+                let _ = result.append_check::<()>(Err(format!("{e:#}")));
+            }
+        }
+
+        print_result(&result);
+    }
+}
+
+fn describe_entry(entry: &JournalEntry) -> String {
+    match entry {
+        JournalEntry::BackedUp { path, backup } => {
+            format!("backed up {path} to {backup}\n")
+        }
+        JournalEntry::Created { path } => format!("created {path}\n"),
+    }
+}
+
+fn print_failure(output: &str) {
+    crate::print_failure("files restore", output);
+}
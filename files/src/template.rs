@@ -0,0 +1,71 @@
+use crate::ownership::User;
+use anyhow::Result;
+use camino::Utf8Path;
+use minijinja::{Environment, UndefinedBehavior};
+use serde_json::{Map, Value};
+use std::fs::read_to_string;
+
+/// Renders templated sources with nk vars and facts about the host
+pub struct Templates {
+    environment: Environment<'static>,
+    facts: Map<String, Value>,
+    vars: Map<String, Value>,
+}
+
+impl Templates {
+    pub fn new(vars: &Map<String, Value>) -> Self {
+        let mut environment = Environment::new();
+        // config files generally care about their trailing newline
+        environment.set_keep_trailing_newline(true);
+        // typos in var names should fail rather than render as blanks
+        environment.set_undefined_behavior(UndefinedBehavior::Strict);
+
+        // facts, which nk vars can override
+        let mut facts = Map::new();
+        facts.insert("os".into(), std::env::consts::OS.into());
+        facts.insert("family".into(), std::env::consts::FAMILY.into());
+        facts.insert("arch".into(), std::env::consts::ARCH.into());
+        facts.insert(
+            "hostname".into(),
+            gethostname::gethostname().to_string_lossy().into(),
+        );
+        if let Ok(username) =
+            std::env::var("USER").or_else(|_| std::env::var("USERNAME"))
+        {
+            facts.insert("username".into(), username.into());
+        }
+        if let Some(home) = dirs::home_dir() {
+            facts.insert("home".into(), home.to_string_lossy().into());
+        }
+
+        Self {
+            environment,
+            facts,
+            vars: vars.clone(),
+        }
+    }
+
+    /// Render a source for the user a state provisions for (none when it's
+    /// whoever runs the plugin)
+    pub fn render(
+        &self,
+        source: &Utf8Path,
+        user: Option<&User>,
+    ) -> Result<Vec<u8>> {
+        let mut context = self.facts.clone();
+        if let Some(user) = user {
+            context.insert("username".into(), user.name.clone().into());
+            context.insert("home".into(), user.home.as_str().into());
+        }
+        context.extend(self.vars.clone());
+
+        let template = read_to_string(source)?;
+        let rendered = self.environment.render_named_str(
+            source.as_str(),
+            &template,
+            Value::Object(context),
+        )?;
+
+        Ok(rendered.into_bytes())
+    }
+}
@@ -0,0 +1,109 @@
+//! Scratch homes, nk sources and state directories for running commands in
+//! tests
+
+use crate::{
+    args::{Provision, ProvisionInfo},
+    provision_states, State, ANY_FAILED,
+};
+use camino::{Utf8Path, Utf8PathBuf};
+use serde_json::Value;
+use std::{
+    fs::{create_dir_all, read_to_string, remove_dir_all, write},
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+/// NOTE: commands find the home and state directories through the
+/// environment, so only one sandbox can exist at a time
+static IN_USE: AtomicBool = AtomicBool::new(false);
+
+pub struct Sandbox {
+    pub dir: Utf8PathBuf,
+}
+
+impl Sandbox {
+    pub fn new(name: &str) -> Self {
+        while IN_USE
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        let dir = Utf8PathBuf::try_from(std::env::temp_dir())
+            .unwrap()
+            .join(format!("nk-files-{name}-{}", std::process::id()));
+        let _ = remove_dir_all(&dir);
+        for subdir in ["home", "state", "src"] {
+            create_dir_all(dir.join(subdir)).unwrap();
+        }
+
+        std::env::set_var("HOME", dir.join("home"));
+        std::env::set_var("XDG_STATE_HOME", dir.join("state"));
+        ANY_FAILED.store(false, Ordering::Relaxed);
+
+        Self { dir }
+    }
+
+    pub fn home(&self, path: &str) -> Utf8PathBuf {
+        self.dir.join("home").join(path)
+    }
+
+    pub fn source(&self, path: &str) -> Utf8PathBuf {
+        self.dir.join("src").join(path)
+    }
+
+    pub fn write(&self, path: &Utf8Path, contents: &str) {
+        if let Some(parent) = path.parent() {
+            create_dir_all(parent).unwrap();
+        }
+        write(path, contents).unwrap();
+    }
+
+    pub fn read(&self, path: &Utf8Path) -> String {
+        read_to_string(path).unwrap()
+    }
+
+    /// Provision the states, returning whether any of them failed
+    pub fn provision(&self, states: Value, prune: bool) -> bool {
+        self.provision_with(states, prune, false, None)
+    }
+
+    pub fn provision_with(
+        &self,
+        states: Value,
+        prune: bool,
+        dry_run: bool,
+        plan: Option<Utf8PathBuf>,
+    ) -> bool {
+        let states: Vec<State> = serde_json::from_value(states).unwrap();
+
+        ANY_FAILED.store(false, Ordering::Relaxed);
+        provision_states(
+            states,
+            Provision {
+                info: ProvisionInfo {
+                    sources: vec![self.dir.join("src")],
+                    vars: serde_json::Map::new(),
+                    user: None,
+                },
+                dry_run,
+                max_diff_lines: 200,
+                prune,
+                backup_retention_days: 30,
+                root: None,
+            },
+            false,
+            plan,
+        );
+
+        ANY_FAILED.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for Sandbox {
+    fn drop(&mut self) {
+        let _ = remove_dir_all(&self.dir);
+        IN_USE.store(false, Ordering::Release);
+    }
+}